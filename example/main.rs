@@ -1,8 +1,11 @@
 extern crate gfcgi;
+extern crate log;
 
-use std::error::Error;
+use std::env;
+use std::io;
 use std::io::{Read, Write};
-use std::thread;
+use std::process;
+use std::time::Duration;
 
 #[derive(Clone, Debug)]
 struct Router;
@@ -35,21 +38,135 @@ impl gfcgi::Handler for Router
             .header_utf8("Content-type", "text/plain");
 
         // send content
-        response.write(
+        response.write_all(
             format!("hello `{}`", host).as_bytes()
         ).expect("send body");
     }
 }
 
-fn main()
+/// Messages of the library on stderr, the error log once detached
+struct Logger;
+
+static LOGGER: Logger = Logger;
+
+impl log::Log for Logger
 {
-    let client = gfcgi::Client::new("127.0.0.1:4128");
+    fn enabled(&self, metadata: &log::Metadata) -> bool
+    {
+        metadata.level() <= log::Level::Info
+    }
 
-    // run listener
-    client.run(Router::new());
+    fn log(&self, record: &log::Record)
+    {
+        if self.enabled(record.metadata()) {
+            // nowhere to report a failed write
+            let _ = writeln!(io::stderr(), "gfcgi: [{}] {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self)
+    {
+    }
+}
+
+/// Command line options
+#[derive(Debug)]
+struct Options
+{
+    listen: String,
+    foreground: bool,
+    pidfile: Option<String>,
+    log: Option<String>,
+    error_log: Option<String>,
+}
+
+impl Options
+{
+    fn parse() -> Options
+    {
+        let mut options = Options {
+            listen: "127.0.0.1:4128".to_owned(),
+            foreground: false,
+            pidfile: None,
+            log: None,
+            error_log: None,
+        };
+
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().unwrap_or_else(|| usage(&format!("{} requires a value", arg)));
 
-    if cfg!(feature = "spawn") {
-        // spawn worker
-        client.run(Router::new());
+            match arg.as_str() {
+                "--listen" => options.listen = value(),
+                "--foreground" => options.foreground = true,
+                "--pidfile" => options.pidfile = Some(value()),
+                "--log" => options.log = Some(value()),
+                "--error-log" => options.error_log = Some(value()),
+                "--help" => usage(""),
+                _ => usage(&format!("unknown option {}", arg)),
+            }
+        }
+
+        options
+    }
+}
+
+fn usage(error: &str) -> !
+{
+    if !error.is_empty() {
+        eprintln!("gfcgi: {}", error);
     }
+    eprintln!("Usage: gfcgi [--listen ADDR] [--foreground] [--pidfile PATH] [--log PATH] [--error-log PATH]");
+
+    process::exit(if error.is_empty() { 0 } else { 2 });
+}
+
+fn main()
+{
+    let options = Options::parse();
+
+    log::set_logger(&LOGGER).expect("Install logger");
+    log::set_max_level(log::LevelFilter::Info);
+
+    let client = gfcgi::Client::builder()
+        .tcp(&options.listen[..])
+        .read_timeout(Duration::from_secs(30))
+        .write_timeout(Duration::from_secs(30))
+        .workers(4)
+        .build()
+        .expect("Bind address");
+
+    // detach after binding, before worker threads start
+    #[cfg(unix)]
+    let _pidfile = {
+        let mut daemon = gfcgi::Daemon::new();
+        daemon.foreground(options.foreground);
+
+        if let Some(ref path) = options.pidfile {
+            daemon.pidfile(path);
+        }
+        if let Some(ref path) = options.log {
+            daemon.stdout(path);
+        }
+        if let Some(ref path) = options.error_log {
+            daemon.stderr(path);
+        }
+
+        let pidfile = daemon.start().unwrap_or_else(|e| {
+            eprintln!("gfcgi: {}", e);
+            process::exit(1);
+        });
+
+        // SIGTERM drains, SIGUSR1 reopens logs after logrotate
+        client.handle_signals(gfcgi::Signals::new().on_reopen(move || {
+            if let Err(e) = daemon.reopen() {
+                eprintln!("gfcgi: reopen logs: {}", e);
+            }
+        })).expect("Install signal handlers");
+
+        pidfile
+    };
+
+    // run listener with worker threads
+    client.run(Router::new());
 }
@@ -0,0 +1,127 @@
+//! Accept error classification and recovery
+use std::cmp;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+#[cfg(unix)]
+use libc;
+
+/// First delay after a resource error
+const BACKOFF_MIN: Duration = Duration::from_millis(10);
+
+/// Longest delay between accept attempts
+const BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+/// Class of a failed `accept`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcceptErrorKind
+{
+    /// Out of descriptors or memory (`EMFILE`, `ENFILE`, `ENOMEM`, `ENOBUFS`),
+    /// accepting is retried with growing delay
+    Resources,
+    /// The pending connection failed before it was accepted (`ECONNABORTED`, `EPROTO`, `EPERM`),
+    /// accepting continues immediately
+    Connection,
+    /// Anything else, retried with growing delay
+    Other,
+}
+
+impl AcceptErrorKind
+{
+    pub(crate) fn of(e: &io::Error) -> AcceptErrorKind
+    {
+        #[cfg(unix)]
+        {
+            match e.raw_os_error() {
+                Some(libc::EMFILE) | Some(libc::ENFILE) | Some(libc::ENOMEM) | Some(libc::ENOBUFS) => {
+                    return AcceptErrorKind::Resources;
+                }
+                Some(libc::ECONNABORTED) | Some(libc::EPROTO) | Some(libc::EPERM) | Some(libc::EINTR) => {
+                    return AcceptErrorKind::Connection;
+                }
+                _ => (),
+            }
+        }
+
+        match e.kind() {
+            io::ErrorKind::OutOfMemory => AcceptErrorKind::Resources,
+            io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::Interrupted => AcceptErrorKind::Connection,
+            _ => AcceptErrorKind::Other,
+        }
+    }
+}
+
+/// Accept error callback
+type HookFn = dyn Fn(&io::Error, AcceptErrorKind) + Send + Sync;
+
+/// Callback for accept errors
+#[derive(Clone)]
+pub(crate) struct AcceptHook(pub Arc<HookFn>);
+
+impl fmt::Debug for AcceptHook
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        f.write_str("AcceptHook")
+    }
+}
+
+/// Growing delay between failed attempts
+pub(crate) struct Backoff
+{
+    delay: Duration,
+}
+
+impl Backoff
+{
+    pub(crate) fn new() -> Backoff
+    {
+        Backoff {
+            delay: BACKOFF_MIN,
+        }
+    }
+
+    /// Sleep before the next attempt
+    pub(crate) fn wait(&mut self)
+    {
+        thread::sleep(self.delay);
+        self.delay = cmp::min(self.delay * 2, BACKOFF_MAX);
+    }
+
+    pub(crate) fn reset(&mut self)
+    {
+        self.delay = BACKOFF_MIN;
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::io;
+
+    use libc;
+
+    use super::AcceptErrorKind;
+
+    #[test]
+    fn error_kinds()
+    {
+        for &errno in &[libc::EMFILE, libc::ENFILE, libc::ENOMEM, libc::ENOBUFS] {
+            assert_eq!(AcceptErrorKind::of(&io::Error::from_raw_os_error(errno)), AcceptErrorKind::Resources);
+        }
+        for &errno in &[libc::ECONNABORTED, libc::EPROTO, libc::EPERM, libc::EINTR] {
+            assert_eq!(AcceptErrorKind::of(&io::Error::from_raw_os_error(errno)), AcceptErrorKind::Connection);
+        }
+        assert_eq!(AcceptErrorKind::of(&io::Error::from_raw_os_error(libc::EBADF)), AcceptErrorKind::Other);
+
+        // without an OS error the kind decides
+        assert_eq!(AcceptErrorKind::of(&io::Error::from(io::ErrorKind::OutOfMemory)), AcceptErrorKind::Resources);
+        assert_eq!(AcceptErrorKind::of(&io::Error::from(io::ErrorKind::ConnectionReset)), AcceptErrorKind::Connection);
+        assert_eq!(AcceptErrorKind::of(&io::Error::other("custom")), AcceptErrorKind::Other);
+    }
+}
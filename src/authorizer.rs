@@ -0,0 +1,114 @@
+//! Authorizer role: the web server asks whether a request may proceed
+use std::io::Write;
+
+use http::{Request, Response, Role};
+use Handler;
+
+/// Decision of an `Authorizer`
+#[derive(Debug, Clone)]
+pub enum Access
+{
+    /// Answered with status 200, the web server passes `variables` to the
+    /// next stage as `Variable-<name>` headers turned into environment variables
+    Allow
+    {
+        variables: Vec<(String, String)>,
+    },
+    /// The web server sends this response to the client, status 200 is turned into 403
+    Deny
+    {
+        status: u16,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    },
+}
+
+/// Callback trait of the authorizer role
+///
+/// The request carries params only, there is no body to read.
+pub trait Authorizer
+{
+    fn authorize(&self, request: &Request) -> Access;
+}
+
+/// Passes authorizer requests to `A`, responder ones to the wrapped handler
+///
+/// ```no_run
+/// # struct Router;
+/// # impl gfcgi::Handler for Router { fn process(&self, _: &mut gfcgi::Request, _: &mut gfcgi::Response) {} }
+/// struct Token;
+///
+/// impl gfcgi::Authorizer for Token
+/// {
+///     fn authorize(&self, request: &gfcgi::Request) -> gfcgi::Access
+///     {
+///         match request.header_utf8(b"HTTP_AUTHORIZATION") {
+///             Some("Bearer secret") => gfcgi::Access::Allow {
+///                 variables: vec![("USER".to_owned(), "admin".to_owned())],
+///             },
+///             _ => gfcgi::Access::Deny {
+///                 status: 401,
+///                 headers: vec![("WWW-Authenticate".to_owned(), "Bearer".to_owned())],
+///                 body: b"Unauthorized".to_vec(),
+///             },
+///         }
+///     }
+/// }
+///
+/// let client = gfcgi::Client::new("127.0.0.1:4128");
+/// client.run(gfcgi::AuthorizerHandler::new(Token, Router));
+/// ```
+pub struct AuthorizerHandler<A, T>
+{
+    authorizer: A,
+    handler: T,
+}
+
+impl<A: Authorizer, T: Handler> AuthorizerHandler<A, T>
+{
+    /// Constructor
+    pub fn new(authorizer: A, handler: T) -> AuthorizerHandler<A, T>
+    {
+        AuthorizerHandler {
+            authorizer,
+            handler,
+        }
+    }
+}
+
+impl<A: Authorizer, T: Handler> Handler for AuthorizerHandler<A, T>
+{
+    fn process(&self, request: &mut Request, response: &mut Response)
+    {
+        if request.role() != Role::Authorizer {
+            return self.handler.process(request, response);
+        }
+
+        match self.authorizer.authorize(request) {
+            Access::Allow { variables } => {
+                response.status(200);
+                for (name, value) in &variables {
+                    response.header_utf8(&format!("Variable-{}", name), value);
+                }
+            }
+            Access::Deny { status, headers, body } => {
+                if status == 200 {
+                    warn!("Authorizer denied with status 200, answer 403");
+                }
+                response.status(if status == 200 { 403 } else { status });
+                for (name, value) in &headers {
+                    response.header_utf8(name, value);
+                }
+
+                if let Err(e) = response.write_all(&body) {
+                    warn!("Send authorizer response: {}", e);
+                }
+            }
+        }
+    }
+
+    fn supports(&self, role: Role) -> bool
+    {
+        role == Role::Authorizer || self.handler.supports(role)
+    }
+}
@@ -0,0 +1,459 @@
+//! Client configuration
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpListener, ToSocketAddrs};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use accept::{AcceptErrorKind, AcceptHook};
+use listener::{self, Listener};
+#[cfg(unix)]
+use listener::UnixSocket;
+#[cfg(unix)]
+use pm::ProcessManager;
+#[cfg(unix)]
+use privileges::Privileges;
+#[cfg(unix)]
+use upgrade;
+use watchdog::Slowlog;
+use Client;
+
+/// Protocol limits applied per transport connection
+#[derive(Debug, Clone, Copy)]
+pub struct Limits
+{
+    /// Maximum concurrent requests multiplexed into one connection
+    pub max_reqs: usize,
+    /// Maximum size of all `PARAMS` records of a request
+    pub max_params: usize,
+    /// Maximum size of request body
+    pub max_body: usize,
+}
+
+impl Default for Limits
+{
+    fn default() -> Limits
+    {
+        Limits {
+            max_reqs: usize::MAX,
+            max_params: usize::MAX,
+            max_body: usize::MAX,
+        }
+    }
+}
+
+/// What happens to connections beyond `ClientBuilder::max_conns`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow
+{
+    /// Stop accepting, connections wait in the backlog
+    Wait,
+    /// Accept and close right away, counted as rejected
+    Close,
+}
+
+/// Options shared by every listener of a `Client`
+#[derive(Debug, Clone)]
+pub(crate) struct Config
+{
+    pub read_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+    pub idle_timeout: Option<Duration>,
+    pub nodelay: bool,
+    pub backlog: Option<i32>,
+    pub workers: usize,
+    pub queue: usize,
+    /// A TCP socket per worker thread
+    pub reuseport: bool,
+    pub max_conns: Option<usize>,
+    pub overflow: Overflow,
+    #[cfg(unix)]
+    pub process_manager: ProcessManager,
+    pub max_requests: Option<u64>,
+    pub request_timeout: Option<Duration>,
+    pub slowlog: Option<Slowlog>,
+    pub limits: Limits,
+    pub accept_hook: Option<AcceptHook>,
+}
+
+impl Default for Config
+{
+    fn default() -> Config
+    {
+        Config {
+            read_timeout: None,
+            write_timeout: None,
+            idle_timeout: None,
+            nodelay: false,
+            backlog: None,
+            workers: 1,
+            queue: 0,
+            reuseport: false,
+            max_conns: None,
+            overflow: Overflow::Wait,
+            #[cfg(unix)]
+            process_manager: ProcessManager::default(),
+            max_requests: None,
+            request_timeout: None,
+            slowlog: None,
+            limits: Limits::default(),
+            accept_hook: None,
+        }
+    }
+}
+
+/// `Client` factory
+///
+/// ```no_run
+/// # use std::time::Duration;
+/// let client = gfcgi::ClientBuilder::new()
+///     .tcp("127.0.0.1:4128")
+///     .read_timeout(Duration::from_secs(30))
+///     .nodelay(true)
+///     .backlog(1024)
+///     .build()
+///     .expect("bind address");
+/// ```
+#[derive(Debug, Default)]
+pub struct ClientBuilder
+{
+    tcp: Vec<Vec<SocketAddr>>,
+    #[cfg(unix)]
+    unix: Vec<UnixSocket>,
+    web_server_addrs: Option<Option<Vec<IpAddr>>>,
+    #[cfg(unix)]
+    privileges: Privileges,
+    config: Config,
+    error: Option<io::Error>,
+}
+
+impl ClientBuilder
+{
+    /// Constructor
+    pub fn new() -> ClientBuilder
+    {
+        ClientBuilder::default()
+    }
+
+    /// Listen on TCP address, may be called several times
+    pub fn tcp<A: ToSocketAddrs>(&mut self, addr: A) -> &mut ClientBuilder
+    {
+        match addr.to_socket_addrs() {
+            Ok(addrs) => self.tcp.push(addrs.collect()),
+            Err(e) => self.error = Some(e),
+        }
+
+        self
+    }
+
+    /// Listen on Unix domain socket, may be called several times
+    #[cfg(unix)]
+    pub fn unix(&mut self, socket: &UnixSocket) -> &mut ClientBuilder
+    {
+        self.unix.push(socket.clone());
+
+        self
+    }
+
+    /// Timeout for reading a request
+    pub fn read_timeout(&mut self, timeout: Duration) -> &mut ClientBuilder
+    {
+        self.config.read_timeout = Some(timeout);
+
+        self
+    }
+
+    /// Timeout for sending a response
+    pub fn write_timeout(&mut self, timeout: Duration) -> &mut ClientBuilder
+    {
+        self.config.write_timeout = Some(timeout);
+
+        self
+    }
+
+    /// Close connection when no request comes for this time
+    pub fn idle_timeout(&mut self, timeout: Duration) -> &mut ClientBuilder
+    {
+        self.config.idle_timeout = Some(timeout);
+
+        self
+    }
+
+    /// Set `TCP_NODELAY` on accepted TCP connections
+    pub fn nodelay(&mut self, nodelay: bool) -> &mut ClientBuilder
+    {
+        self.config.nodelay = nodelay;
+
+        self
+    }
+
+    /// Pending connections queue size
+    pub fn backlog(&mut self, backlog: i32) -> &mut ClientBuilder
+    {
+        self.config.backlog = Some(backlog);
+
+        self
+    }
+
+    /// Number of threads serving connections in `run`
+    pub fn workers(&mut self, workers: usize) -> &mut ClientBuilder
+    {
+        self.config.workers = workers.max(1);
+
+        self
+    }
+
+    /// Accepted connections waiting for a free worker, by default handed over directly
+    ///
+    /// Accepting pauses while the queue is full, further connections wait in the backlog.
+    pub fn queue(&mut self, queue: usize) -> &mut ClientBuilder
+    {
+        self.config.queue = queue;
+
+        self
+    }
+
+    /// Bind a TCP socket per `workers` thread with `SO_REUSEPORT`
+    ///
+    /// The kernel spreads connections among the sockets, so threads do not wake
+    /// up for the same connection, yet a connection waits for its thread while
+    /// others are idle. `queue` does not apply, Unix domain sockets are still
+    /// shared. `run_prefork` uses the first socket only. Another process of the
+    /// same user binding the address with this option joins the group.
+    #[cfg(target_os = "linux")]
+    pub fn reuseport(&mut self, reuseport: bool) -> &mut ClientBuilder
+    {
+        self.config.reuseport = reuseport;
+
+        self
+    }
+
+    /// Fixed number of worker processes forked by `run_prefork`
+    #[cfg(unix)]
+    pub fn processes(&mut self, processes: usize) -> &mut ClientBuilder
+    {
+        self.process_manager(ProcessManager::Static {
+            children: processes.max(1),
+        })
+    }
+
+    /// Sizing of worker processes forked by `run_prefork`
+    ///
+    /// `build` fails with `InvalidInput` when spare limits of `Dynamic` do not fit together.
+    #[cfg(unix)]
+    pub fn process_manager(&mut self, process_manager: ProcessManager) -> &mut ClientBuilder
+    {
+        self.config.process_manager = process_manager;
+
+        self
+    }
+
+    /// Requests served by a worker process of `run_prefork` before it is replaced
+    ///
+    /// The worker stops accepting, sends the response, closes the connection
+    /// and exits, a fresh one is forked meanwhile. Works around memory leaks of the handler, threads
+    /// of `run` share memory and are not recycled.
+    #[cfg(unix)]
+    pub fn max_requests(&mut self, max_requests: u64) -> &mut ClientBuilder
+    {
+        self.config.max_requests = Some(max_requests.max(1));
+
+        self
+    }
+
+    /// Answer `504` to the web server when the handler runs longer
+    ///
+    /// The handler thread can not be stopped, its further output is discarded
+    /// and the connection is closed. A response already started is left unended.
+    pub fn request_timeout(&mut self, timeout: Duration) -> &mut ClientBuilder
+    {
+        self.config.request_timeout = Some(timeout);
+
+        self
+    }
+
+    /// Log requests running longer than `timeout` to `path`, or with `log` crate when `None`
+    ///
+    /// An entry names `SCRIPT_NAME`, `REQUEST_URI`, duration and the worker thread,
+    /// backtraces of other threads can not be captured.
+    pub fn slowlog<P: AsRef<Path>>(&mut self, path: Option<P>, timeout: Duration) -> &mut ClientBuilder
+    {
+        self.config.slowlog = Some(Slowlog {
+            path: path.map(|p| p.as_ref().to_path_buf()),
+            timeout,
+        });
+
+        self
+    }
+
+    /// Open transport connections of a process, queued ones included
+    ///
+    /// `run` advertises the value to the web server as `FCGI_MAX_CONNS`,
+    /// `workers` plus `queue` by default.
+    pub fn max_conns(&mut self, max_conns: usize, overflow: Overflow) -> &mut ClientBuilder
+    {
+        self.config.max_conns = Some(max_conns.max(1));
+        self.config.overflow = overflow;
+
+        self
+    }
+
+    /// Protocol limits
+    pub fn limits(&mut self, limits: Limits) -> &mut ClientBuilder
+    {
+        self.config.limits = limits;
+
+        self
+    }
+
+    /// Called on every failed `accept`, the listener keeps running
+    pub fn on_accept_error<F>(&mut self, hook: F) -> &mut ClientBuilder
+        where F: Fn(&io::Error, AcceptErrorKind) + Send + Sync + 'static
+    {
+        self.config.accept_hook = Some(AcceptHook(Arc::new(hook)));
+
+        self
+    }
+
+    /// Switch to this user when `run` starts, name or numeric id
+    ///
+    /// The group and supplementary groups of the user are taken unless set.
+    /// See `Client::drop_privileges`.
+    #[cfg(unix)]
+    pub fn user(&mut self, user: &str) -> &mut ClientBuilder
+    {
+        self.privileges.user = Some(user.to_owned());
+
+        self
+    }
+
+    /// Switch to this group when `run` starts, name or numeric id
+    #[cfg(unix)]
+    pub fn group(&mut self, group: &str) -> &mut ClientBuilder
+    {
+        self.privileges.group = Some(group.to_owned());
+
+        self
+    }
+
+    /// Supplementary groups set when `run` starts, names or numeric ids
+    #[cfg(unix)]
+    pub fn groups(&mut self, groups: &[&str]) -> &mut ClientBuilder
+    {
+        self.privileges.groups = Some(groups.iter().map(|g| g.to_string()).collect());
+
+        self
+    }
+
+    /// Change root directory when `run` starts, before the user is switched
+    #[cfg(unix)]
+    pub fn chroot<P: AsRef<Path>>(&mut self, path: P) -> &mut ClientBuilder
+    {
+        self.privileges.chroot = Some(path.as_ref().to_path_buf());
+
+        self
+    }
+
+    /// Allowed web server addresses, overrides `FCGI_WEB_SERVER_ADDRS`
+    pub fn web_server_addrs(&mut self, addrs: Option<Vec<IpAddr>>) -> &mut ClientBuilder
+    {
+        self.web_server_addrs = Some(addrs);
+
+        self
+    }
+
+    /// Bind all listeners, privileges are dropped later by `Client::drop_privileges`
+    ///
+    /// After `Client::upgrade` sockets of the previous process are taken instead.
+    /// Fails when a name given to `user`, `group` or `groups` is unknown.
+    pub fn build(&mut self) -> io::Result<Client>
+    {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        #[cfg(unix)]
+        self.config.process_manager.validate()?;
+
+        #[cfg(unix)]
+        self.privileges.validate()?;
+
+        let client = Client::with_config(self.config.clone());
+
+        if let Some(ref addrs) = self.web_server_addrs {
+            client.web_server_addrs(addrs.clone());
+        }
+
+        #[cfg(unix)]
+        {
+            if let Some(inherited) = upgrade::inherited()? {
+                for group in inherited.fds {
+                    let mut sockets = group.into_iter()
+                        .map(|fd| Listener::from_fd(fd).and_then(|l| self.listen(l)))
+                        .collect::<io::Result<Vec<_>>>()?;
+
+                    // `SO_REUSEPORT` sockets: one per worker thread, every thread accepts on the rest
+                    let per_thread = if self.config.reuseport { self.config.workers.clamp(1, sockets.len()) } else { 1 };
+                    let rest = sockets.split_off(per_thread);
+                    let first = sockets.remove(0);
+                    client.add_sharded(first, sockets)?;
+                    for listener in rest {
+                        client.add_listener(listener)?;
+                    }
+                }
+                *client.inner.ready.lock().unwrap() = inherited.ready;
+
+                return Ok(self.keep_privileges(client));
+            }
+        }
+
+        for addrs in &self.tcp {
+            #[cfg(target_os = "linux")]
+            {
+                if self.config.reuseport {
+                    let mut sockets = listener::bind_reuseport(addrs, self.config.workers)?
+                        .into_iter()
+                        .map(|l| self.listen(l))
+                        .collect::<io::Result<Vec<_>>>()?;
+                    let first = sockets.remove(0);
+                    client.add_sharded(first, sockets)?;
+                    continue;
+                }
+            }
+
+            client.add_listener(self.listen(Listener::Tcp(TcpListener::bind(&addrs[..])?))?)?;
+        }
+
+        #[cfg(unix)]
+        for socket in &self.unix {
+            client.add_listener(self.listen(Listener::Unix(socket.bind()?))?)?;
+        }
+
+        Ok(self.keep_privileges(client))
+    }
+
+    /// Hand `user`, `group`, `groups` and `chroot` to `Client::drop_privileges`
+    #[cfg(unix)]
+    fn keep_privileges(&self, client: Client) -> Client
+    {
+        if !self.privileges.is_empty() {
+            *client.inner.privileges.lock().unwrap() = Some(self.privileges.clone());
+        }
+
+        client
+    }
+
+    #[cfg(not(unix))]
+    fn keep_privileges(&self, client: Client) -> Client
+    {
+        client
+    }
+
+    fn listen(&self, listener: Listener) -> io::Result<Listener>
+    {
+        if let Some(backlog) = self.config.backlog {
+            listener::set_backlog(&listener, backlog)?;
+        }
+
+        Ok(listener)
+    }
+}
@@ -0,0 +1,265 @@
+//! Registry of transport connections in progress
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::io::{Read, Write};
+use std::net::Shutdown;
+use std::sync::{Arc, Mutex, TryLockError};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Instant;
+
+use fastcgi;
+use fastcgi::Readable;
+use http::{Request, Response};
+use listener::Stream;
+
+/// Request running in the handler
+pub(crate) struct Current
+{
+    pub id: u16,
+    pub script_name: String,
+    pub request_uri: String,
+    pub started: Instant,
+    /// Name of the thread running the handler
+    pub thread: String,
+    pub slow_logged: bool,
+    pub timed_out: bool,
+}
+
+/// Served connection
+pub(crate) struct Connection
+{
+    /// Clone of the served socket to interrupt blocking calls
+    stream: Stream,
+    /// Handler is running
+    pub busy: AtomicBool,
+    pub current: Mutex<Option<Current>>,
+    /// Held while records are written
+    writing: Mutex<()>,
+    /// Response is taken over on behalf of the handler, its writes are discarded
+    cut: AtomicBool,
+    /// Handler wrote some response
+    sent: AtomicBool,
+    /// Handler wrote the end of its response
+    ended: AtomicBool,
+}
+
+impl Connection
+{
+    /// Interrupt connection
+    pub(crate) fn shutdown(&self, how: Shutdown)
+    {
+        // connection may be already closed by peer
+        let _ = self.stream.shutdown(how);
+    }
+
+    /// Track the request passed to the handler
+    pub(crate) fn begin(&self, request: &Request)
+    {
+        let param = |key: &[u8]| request.header(key)
+            .map(|v| String::from_utf8_lossy(v).into_owned())
+            .unwrap_or_default();
+
+        *self.current.lock().unwrap() = Some(Current {
+            id: request.get_id(),
+            script_name: param(b"SCRIPT_NAME"),
+            request_uri: param(b"REQUEST_URI"),
+            started: Instant::now(),
+            thread: thread::current().name().unwrap_or("unnamed").to_owned(),
+            slow_logged: false,
+            timed_out: false,
+        });
+        self.sent.store(false, Ordering::SeqCst);
+        self.ended.store(false, Ordering::SeqCst);
+        self.busy.store(true, Ordering::SeqCst);
+    }
+
+    /// Handler returned, `false` if its response was cut off
+    pub(crate) fn end(&self) -> bool
+    {
+        self.busy.store(false, Ordering::SeqCst);
+        self.current.lock().unwrap().take();
+
+        !self.cut.load(Ordering::SeqCst)
+    }
+
+    /// Answer the running request with `status` and close the connection
+    ///
+    /// Further writes of the handler are discarded. When the handler already
+    /// started the response, the connection is closed without ending it so the
+    /// web server sees the response cut short. A handler blocked in a write
+    /// is not waited for, the connection is closed without a response then.
+    pub(crate) fn cut(&self, id: u16, status: u16, body: &[u8]) -> io::Result<()>
+    {
+        let _writing = match self.writing.try_lock() {
+            Ok(writing) => writing,
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+            Err(TryLockError::WouldBlock) => {
+                // the peer may not read at all, a record half written can not be ended
+                if !self.ended.load(Ordering::SeqCst) && !self.cut.swap(true, Ordering::SeqCst) {
+                    warn!("Request {} is blocked in a write, close the connection", id);
+                    self.shutdown(Shutdown::Both);
+                }
+                return Ok(());
+            }
+        };
+        // the handler returned in the meantime
+        if self.ended.load(Ordering::SeqCst) || self.cut.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        // END_REQUEST after part of the response would pass it for complete
+        if self.sent.load(Ordering::SeqCst) {
+            warn!("Request {} already sent part of its response, close the connection", id);
+            self.shutdown(Shutdown::Both);
+            return Ok(());
+        }
+
+        let stream = RefCell::new(self.stream.try_clone()?);
+        let mut response = Response::new(&stream, id);
+        response.status(status).header_utf8("Content-type", "text/plain");
+        let result = response.write_all(body).and_then(|_| response.flush());
+
+        // the web server must not reuse the connection, the handler is still running
+        self.shutdown(Shutdown::Both);
+
+        result
+    }
+}
+
+/// Transport of a served connection, records of the handler are written under the connection lock
+pub(crate) struct Guarded
+{
+    stream: Stream,
+    connection: Arc<Connection>,
+}
+
+impl Guarded
+{
+    pub(crate) fn new(stream: Stream, connection: Arc<Connection>) -> Guarded
+    {
+        Guarded {
+            stream,
+            connection,
+        }
+    }
+
+    pub(crate) fn get_ref(&self) -> &Stream
+    {
+        &self.stream
+    }
+
+    pub(crate) fn get_mut(&mut self) -> &mut Stream
+    {
+        &mut self.stream
+    }
+}
+
+impl Read for Guarded
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>
+    {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for Guarded
+{
+    /// Writes everything, `Response` passes whole records
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize>
+    {
+        let _writing = self.connection.writing.lock().unwrap();
+        if self.connection.cut.load(Ordering::SeqCst) {
+            return Ok(buf.len());
+        }
+
+        self.connection.sent.store(true, Ordering::SeqCst);
+        self.stream.write_all(buf)?;
+        if ends_request(buf) {
+            self.connection.ended.store(true, Ordering::SeqCst);
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()>
+    {
+        self.stream.flush()
+    }
+}
+
+/// Records in `buf` include `END_REQUEST`, only the running request is ended while the handler runs
+fn ends_request(buf: &[u8]) -> bool
+{
+    let mut pos = 0;
+    while pos + fastcgi::HEADER_LEN <= buf.len() {
+        let h = fastcgi::Header::read(&buf[pos..pos + fastcgi::HEADER_LEN]);
+        if h.type_ == fastcgi::END_REQUEST {
+            return true;
+        }
+        pos += fastcgi::HEADER_LEN + h.content_length as usize + h.padding_length as usize;
+    }
+
+    false
+}
+
+/// All connections of a `Client`
+#[derive(Default)]
+pub(crate) struct Connections
+{
+    next_id: AtomicUsize,
+    map: Mutex<HashMap<usize, Arc<Connection>>>,
+}
+
+impl Connections
+{
+    /// Track connection until the guard is dropped
+    pub(crate) fn register(&self, stream: &Stream) -> io::Result<Registered<'_>>
+    {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let connection = Arc::new(Connection {
+            stream: stream.try_clone()?,
+            busy: AtomicBool::new(false),
+            current: Mutex::new(None),
+            writing: Mutex::new(()),
+            cut: AtomicBool::new(false),
+            sent: AtomicBool::new(false),
+            ended: AtomicBool::new(false),
+        });
+        self.map.lock().unwrap().insert(id, connection.clone());
+
+        Ok(Registered {
+            connections: self,
+            id,
+            connection,
+        })
+    }
+
+    /// Snapshot of connections in progress
+    pub(crate) fn list(&self) -> Vec<Arc<Connection>>
+    {
+        self.map.lock().unwrap().values().cloned().collect()
+    }
+
+    pub(crate) fn len(&self) -> usize
+    {
+        self.map.lock().unwrap().len()
+    }
+}
+
+/// Registration guard
+pub(crate) struct Registered<'c>
+{
+    connections: &'c Connections,
+    id: usize,
+    pub connection: Arc<Connection>,
+}
+
+impl<'c> Drop for Registered<'c>
+{
+    fn drop(&mut self)
+    {
+        self.connections.map.lock().unwrap().remove(&self.id);
+    }
+}
@@ -0,0 +1,303 @@
+//! Detach from the terminal, pidfile
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::mem;
+use std::os::unix::fs::{FileExt, MetadataExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use libc;
+
+use upgrade;
+
+/// Background server setup
+///
+/// Bind listeners before `start`, so errors are seen on the terminal,
+/// and start threads after it, they do not survive `fork`.
+///
+/// ```no_run
+/// let _pidfile = gfcgi::Daemon::new()
+///     .pidfile("/run/app.pid")
+///     .stdout("/var/log/app/access.log")
+///     .stderr("/var/log/app/error.log")
+///     .start()
+///     .expect("daemonize");
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Daemon
+{
+    pidfile: Option<PathBuf>,
+    stdout: Option<PathBuf>,
+    stderr: Option<PathBuf>,
+    foreground: bool,
+}
+
+impl Daemon
+{
+    /// Constructor
+    pub fn new() -> Daemon
+    {
+        Daemon::default()
+    }
+
+    /// Write process id to the file, locked while the process runs
+    pub fn pidfile<P: AsRef<Path>>(&mut self, path: P) -> &mut Daemon
+    {
+        self.pidfile = Some(path.as_ref().to_path_buf());
+
+        self
+    }
+
+    /// Append standard output to the file
+    pub fn stdout<P: AsRef<Path>>(&mut self, path: P) -> &mut Daemon
+    {
+        self.stdout = Some(absolute(path.as_ref()));
+
+        self
+    }
+
+    /// Append standard error to the file
+    pub fn stderr<P: AsRef<Path>>(&mut self, path: P) -> &mut Daemon
+    {
+        self.stderr = Some(absolute(path.as_ref()));
+
+        self
+    }
+
+    /// Stay attached to the terminal, pidfile and redirection still apply
+    pub fn foreground(&mut self, foreground: bool) -> &mut Daemon
+    {
+        self.foreground = foreground;
+
+        self
+    }
+
+    /// Detach unless `foreground` is set
+    ///
+    /// The calling process exits once the daemon is set up, with status 1
+    /// if it failed. Fails when the pidfile is locked by a running process,
+    /// unless this process was started by its `Client::upgrade`.
+    pub fn start(&self) -> io::Result<Option<Pidfile>>
+    {
+        let mut pidfile = match self.pidfile {
+            Some(ref path) => Some(Pidfile::lock(path)?),
+            None => None,
+        };
+        let stdout = self.stdout.as_ref().map(|p| open_log(p)).transpose()?;
+        let stderr = self.stderr.as_ref().map(|p| open_log(p)).transpose()?;
+
+        let ready = if self.foreground { None } else { Some(detach()?) };
+
+        if let Some(ref file) = stdout {
+            redirect(file, libc::STDOUT_FILENO)?;
+        }
+        if let Some(ref file) = stderr {
+            redirect(file, libc::STDERR_FILENO)?;
+        }
+
+        if let Some(ref mut pidfile) = pidfile {
+            pidfile.write_pid()?;
+        }
+
+        if let Some(mut ready) = ready {
+            ready.write_all(b"1")?;
+        }
+
+        Ok(pidfile)
+    }
+
+    /// Open the `stdout` and `stderr` files again, e.g. after logrotate
+    ///
+    /// ```no_run
+    /// let mut daemon = gfcgi::Daemon::new();
+    /// daemon.stderr("/var/log/app/error.log");
+    /// let _pidfile = daemon.start().expect("daemonize");
+    ///
+    /// let client = gfcgi::Client::new("127.0.0.1:4128");
+    /// client.handle_signals(gfcgi::Signals::new().on_reopen(move || {
+    ///     if let Err(e) = daemon.reopen() {
+    ///         eprintln!("Reopen logs: {}", e);
+    ///     }
+    /// })).expect("install signal handlers");
+    /// ```
+    pub fn reopen(&self) -> io::Result<()>
+    {
+        if let Some(ref path) = self.stdout {
+            redirect(&open_log(path)?, libc::STDOUT_FILENO)?;
+        }
+        if let Some(ref path) = self.stderr {
+            redirect(&open_log(path)?, libc::STDERR_FILENO)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Locked pidfile, removed on drop unless a process started by `Client::upgrade` took it over
+#[derive(Debug)]
+pub struct Pidfile
+{
+    file: File,
+    path: PathBuf,
+}
+
+impl Pidfile
+{
+    fn lock(path: &Path) -> io::Result<Pidfile>
+    {
+        // the daemon changes directory
+        let path = env::current_dir()?.join(path);
+
+        // the previous process holds the lock through the same open file
+        if let Some(file) = upgrade::inherited_pidfile()? {
+            if is_same_file(&file, &path) {
+                upgrade::pass_pidfile(Some(file.as_raw_fd()));
+                return Ok(Pidfile { file, path });
+            }
+        }
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o644)
+            .open(&path)?;
+
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == -1 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::WouldBlock {
+                return Err(e);
+            }
+
+            let mut pid = String::new();
+            let _ = file.read_to_string(&mut pid);
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{} is locked by running process {}", path.display(), pid.trim())));
+        }
+
+        upgrade::pass_pidfile(Some(file.as_raw_fd()));
+
+        Ok(Pidfile { file, path })
+    }
+
+    fn write_pid(&mut self) -> io::Result<()>
+    {
+        // the file offset is shared with the process that passed the pidfile
+        self.file.set_len(0)?;
+        self.file.write_all_at(format!("{}\n", process::id()).as_bytes(), 0)?;
+
+        self.file.sync_all()
+    }
+
+    pub fn path(&self) -> &Path
+    {
+        &self.path
+    }
+}
+
+impl Drop for Pidfile
+{
+    fn drop(&mut self)
+    {
+        upgrade::pass_pidfile(None);
+
+        // a process started by `Client::upgrade` wrote its own pid
+        let pid = fs::read_to_string(&self.path).unwrap_or_default();
+        if pid.trim() == process::id().to_string() {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// `file` is still the one at `path`
+fn is_same_file(file: &File, path: &Path) -> bool
+{
+    match (file.metadata(), fs::metadata(path)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+/// Relative paths would change meaning once the daemon leaves the directory
+fn absolute(path: &Path) -> PathBuf
+{
+    env::current_dir().map(|dir| dir.join(path)).unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn open_log(path: &Path) -> io::Result<File>
+{
+    OpenOptions::new().create(true).append(true).mode(0o640).open(path)
+}
+
+/// Replace a standard descriptor
+fn redirect(file: &File, fd: libc::c_int) -> io::Result<()>
+{
+    match unsafe { libc::dup2(file.as_raw_fd(), fd) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Double fork and new session, the calling process exits when the daemon reports readiness
+fn detach() -> io::Result<UnixStream>
+{
+    let (mut wait, ready) = UnixStream::pair()?;
+
+    match unsafe { libc::fork() } {
+        -1 => return Err(io::Error::last_os_error()),
+        0 => drop(wait),
+        _ => {
+            drop(ready);
+            let mut buf = [0; 1];
+            match wait.read(&mut buf) {
+                Ok(1) => process::exit(0),
+                _ => {
+                    eprintln!("Daemon exited before it was ready");
+                    process::exit(1);
+                }
+            }
+        }
+    }
+
+    // no controlling terminal
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // session leader could acquire a terminal again
+    match unsafe { libc::fork() } {
+        -1 => return Err(io::Error::last_os_error()),
+        0 => (),
+        // leave buffers and exit handlers to the daemon
+        _ => unsafe { libc::_exit(0) },
+    }
+
+    if unsafe { libc::chdir(b"/\0".as_ptr() as *const libc::c_char) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let null = OpenOptions::new().read(true).write(true).open("/dev/null")?;
+    for &fd in &[libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        // `FCGI_LISTENSOCK_FILENO` is the listener passed by the web server
+        if fd == libc::STDIN_FILENO && is_socket(fd) {
+            continue;
+        }
+        redirect(&null, fd)?;
+    }
+
+    Ok(ready)
+}
+
+fn is_socket(fd: libc::c_int) -> bool
+{
+    let mut stat: libc::stat = unsafe { mem::zeroed() };
+    if unsafe { libc::fstat(fd, &mut stat) } == -1 {
+        return false;
+    }
+
+    stat.st_mode & libc::S_IFMT == libc::S_IFSOCK
+}
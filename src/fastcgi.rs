@@ -3,6 +3,9 @@
 /// Listening socket file number
 pub const LISTENSOCK_FILENO: u8 = 0;
 
+/// Environment variable with comma-separated IP addresses of allowed web servers
+pub const WEB_SERVER_ADDRS: &str = "FCGI_WEB_SERVER_ADDRS";
+
 /// FCGI record header
 #[derive(Debug)]
 pub struct Header
@@ -140,20 +143,20 @@ pub const UNKNOWN_ROLE: u8 = 3;
 /// Names for GET_VALUES / GET_VALUES_RESULT records.
 ///
 /// The maximum number of concurrent transport connections this application will accept, e.g. "1" or "10".
-pub const MAX_CONNS: &'static str = "MAX_CONNS";
+pub const MAX_CONNS: &str = "FCGI_MAX_CONNS";
 
 /// Names for GET_VALUES / GET_VALUES_RESULT records.
 ///
 /// The maximum number of concurrent requests this application will accept, e.g. "1" or "50".
-pub const MAX_REQS: &'static str = "MAX_REQS";
+pub const MAX_REQS: &str = "FCGI_MAX_REQS";
 
 /// Names for GET_VALUES / GET_VALUES_RESULT records.
 ///
 /// If this application does not multiplex connections (i.e. handle concurrent requests over each connection), "1" otherwise.
-pub const MPXS_CONNS: &'static str = "MPXS_CONNS";
-
+pub const MPXS_CONNS: &str = "FCGI_MPXS_CONNS";
 
-struct UnknownTypeBody
+/// Body of UNKNOWN_TYPE record, the type the application does not understand
+pub struct UnknownTypeBody
 {
     pub type_: u8,
     pub reserved: [u8; 7],
@@ -3,15 +3,27 @@ use fastcgi;
 use fastcgi::{Readable, Writable};
 
 use std::io;
-use std::io::{Read, Write};
+use std::cell::RefCell;
 use std::collections::HashMap;
-use std::net::TcpStream;
+use std::fmt;
 use std::str;
 
+use {Limits, Transport};
+
 extern crate byteorder;
 use self::byteorder::{ByteOrder, BigEndian};
 
-#[derive(Debug)]
+/// Role of the application in a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role
+{
+    Responder,
+    Authorizer,
+    Filter,
+    /// Role the protocol does not define
+    Other(u16),
+}
+
 pub struct Request<'sr>
 {
     id: u16,
@@ -19,8 +31,13 @@ pub struct Request<'sr>
     flags: u8,
     headers: HashMap<Vec<u8>, Vec<u8>>,
     buf: Vec<u8>,
-    stream: &'sr TcpStream,
+    stream: &'sr RefCell<dyn Transport + 'sr>,
     pending: bool,
+    limits: Limits,
+    params_len: usize,
+    body_len: usize,
+    /// Answered to `GET_VALUES` coming in between body records
+    max_conns: Option<usize>,
 }
 
 impl<'sr> Request<'sr>
@@ -68,16 +85,20 @@ impl<'sr> Request<'sr>
     }
 
     /// Constructor
-    pub(crate) fn new(stream: &'sr TcpStream, id: u16) -> Request
+    pub(crate) fn new(stream: &'sr RefCell<dyn Transport + 'sr>, id: u16, limits: Limits, max_conns: Option<usize>) -> Request<'sr>
     {
         Request {
-            id: id,
+            id,
             role: 0,
             flags: 0,
             headers: HashMap::new(),
             buf: Vec::new(),
-            stream: stream,
+            stream,
             pending: true,
+            limits,
+            params_len: 0,
+            body_len: 0,
+            max_conns,
         }
     }
 
@@ -89,6 +110,31 @@ impl<'sr> Request<'sr>
         self.flags = begin_request.flags;
     }
 
+    /// Request with options of begin record
+    pub(crate) fn begin(mut self, data: Vec<u8>) -> Request<'sr>
+    {
+        self.add_options(data);
+
+        self
+    }
+
+    /// Role asked by the web server
+    pub fn role(&self) -> Role
+    {
+        match self.role {
+            role if role == u16::from(fastcgi::RESPONDER) => Role::Responder,
+            role if role == u16::from(fastcgi::AUTHORIZER) => Role::Authorizer,
+            role if role == u16::from(fastcgi::FILTER) => Role::Filter,
+            role => Role::Other(role),
+        }
+    }
+
+    /// Web server keeps the connection open after the request
+    pub(crate) fn keep_conn(&self) -> bool
+    {
+        self.flags & fastcgi::KEEP_CONN != 0
+    }
+
     /// Add param pairs
     pub(crate) fn add_param(&mut self, data: Vec<u8>)
     {
@@ -102,49 +148,121 @@ impl<'sr> Request<'sr>
     }
 
     /// Read FastCGI header
-    pub(crate) fn fcgi_header(mut stream: &TcpStream) -> fastcgi::Header
+    pub(crate) fn fcgi_header(stream: &RefCell<dyn Transport + '_>) -> io::Result<fastcgi::Header>
     {
         let mut buf: [u8; fastcgi::HEADER_LEN] = [0; fastcgi::HEADER_LEN];
-        stream.read(&mut buf).expect("Read fcgi header");
-        fastcgi::Header::read(&buf)
+        stream.borrow_mut().read_exact(&mut buf)?;
+        Ok(fastcgi::Header::read(&buf))
     }
 
-    pub(crate) fn fcgi_body(stream: &TcpStream, h: &fastcgi::Header) -> Vec<u8>
+    pub(crate) fn fcgi_body(stream: &RefCell<dyn Transport + '_>, h: &fastcgi::Header) -> io::Result<Vec<u8>>
     {
         let body = match h.content_length {
             0 => Vec::new(),
-            _ => Self::stream_read(stream, h.content_length as usize),
+            _ => Self::stream_read(stream, h.content_length as usize)?,
         };
 
         if h.padding_length > 0 {
-            Self::stream_read(stream, h.padding_length as usize);
+            Self::stream_read(stream, h.padding_length as usize)?;
         }
 
-        body
+        Ok(body)
     }
 
-    pub(crate) fn stream_read(mut stream: &TcpStream, length: usize) -> Vec<u8>
+    pub(crate) fn stream_read(stream: &RefCell<dyn Transport + '_>, length: usize) -> io::Result<Vec<u8>>
     {
-        let mut body: Vec<u8> = Vec::with_capacity(length);
-        unsafe {
-            body.set_len(length);
-        }
+        let mut body: Vec<u8> = vec![0; length];
+        stream.borrow_mut().read_exact(&mut body)?;
 
-        match stream.read(&mut body) {
-            Ok(_len) if _len == length => body,
-            Ok(_len) => panic!("{} bytes readed, expected {}", _len, length),
-            Err(e)  => panic!("{}", e),
-        }
+        Ok(body)
     }
 
-    pub(crate) fn fcgi_record(&mut self, h: fastcgi::Header, body: Vec<u8>)
+    pub(crate) fn fcgi_record(&mut self, h: fastcgi::Header, body: Vec<u8>) -> io::Result<()>
     {
         match h.type_ {
             fastcgi::BEGIN_REQUEST => self.add_options(body),
-            fastcgi::PARAMS => self.add_param(body),
-            fastcgi::STDIN => self.buf.extend(body),
-            _ => panic!("Wrong FastCGI request header"),
+            fastcgi::PARAMS => {
+                self.params_len += body.len();
+                if self.params_len > self.limits.max_params {
+                    return Err(limit_error("params", self.limits.max_params));
+                }
+                self.add_param(body)
+            }
+            fastcgi::STDIN => self.add_body(body)?,
+            // file data of the filter role, not passed to the handler
+            fastcgi::DATA => (),
+            type_ => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unexpected record type {}", type_))),
+        }
+
+        Ok(())
+    }
+
+    /// Skip the body the handler left unread, records of the next request follow it
+    ///
+    /// A closing connection is not read again, other roles may send no body at all.
+    pub(crate) fn discard(&mut self) -> io::Result<()>
+    {
+        if !self.keep_conn() || self.role() != Role::Responder {
+            return Ok(());
+        }
+
+        self.buf.clear();
+        while self.pending {
+            self.stdin_record()?;
         }
+
+        Ok(())
+    }
+
+    /// Next STDIN data of the request, `None` once the stream ended
+    fn stdin_record(&mut self) -> io::Result<Option<Vec<u8>>>
+    {
+        loop {
+            let h = Self::fcgi_header(self.stream)?;
+            let body = Self::fcgi_body(self.stream, &h)?;
+
+            if h.request_id == fastcgi::NULL_REQUEST_ID {
+                management(self.stream, &h, body, self.max_conns)?;
+            } else if !is_request_record(h.type_) {
+                unknown_type(self.stream, h.type_)?;
+            } else if h.request_id == self.id {
+                match h.type_ {
+                    fastcgi::STDIN if !body.is_empty() => return Ok(Some(body)),
+                    fastcgi::STDIN | fastcgi::ABORT_REQUEST => {
+                        self.pending = false;
+                        return Ok(None);
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    /// Buffer STDIN data
+    fn add_body(&mut self, body: Vec<u8>) -> io::Result<()>
+    {
+        self.body_len += body.len();
+        if self.body_len > self.limits.max_body {
+            return Err(limit_error("body", self.limits.max_body));
+        }
+        self.buf.extend(body);
+
+        Ok(())
+    }
+}
+
+impl<'sr> fmt::Debug for Request<'sr>
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        f.debug_struct("Request")
+            .field("id", &self.id)
+            .field("role", &self.role)
+            .field("flags", &self.flags)
+            .field("headers", &self.headers)
+            .field("buf", &self.buf)
+            .field("pending", &self.pending)
+            .finish()
     }
 }
 
@@ -153,13 +271,10 @@ impl<'sr> io::Read for Request<'sr>
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>
     {
         while self.buf.len() < buf.len() && self.pending {
-            let h = Self::fcgi_header(self.stream);
-            if h.content_length == 0 {
-                self.pending = false;
-                break;
+            match self.stdin_record()? {
+                Some(body) => self.add_body(body)?,
+                None => break,
             }
-            let body = Self::fcgi_body(self.stream, &h);
-            self.buf.extend(body);
         }
 
         let end = if buf.len() > self.buf.len() {
@@ -178,6 +293,72 @@ impl<'sr> io::Read for Request<'sr>
 }
 
 
+fn limit_error(what: &str, limit: usize) -> io::Error
+{
+    io::Error::new(io::ErrorKind::InvalidData, format!("request {} exceeds {} bytes", what, limit))
+}
+
+/// Record types sent by the web server for a request, others are answered with `UNKNOWN_TYPE`
+pub(crate) fn is_request_record(type_: u8) -> bool
+{
+    matches!(type_, fastcgi::BEGIN_REQUEST | fastcgi::ABORT_REQUEST | fastcgi::PARAMS | fastcgi::STDIN | fastcgi::DATA)
+}
+
+/// Answer a record about the connection itself rather than a request
+pub(crate) fn management(stream: &RefCell<dyn Transport + '_>, h: &fastcgi::Header, body: Vec<u8>, max_conns: Option<usize>) -> io::Result<()>
+{
+    if h.type_ != fastcgi::GET_VALUES {
+        return unknown_type(stream, h.type_);
+    }
+
+    let mut result = Vec::new();
+    for name in value_names(body) {
+        let value = match str::from_utf8(&name) {
+            Ok(fastcgi::MAX_CONNS) | Ok(fastcgi::MAX_REQS) => max_conns.map(|max| max.to_string()),
+            // records of a request are read in turn, others would be mixed in
+            Ok(fastcgi::MPXS_CONNS) => Some("0".to_owned()),
+            _ => None,
+        };
+
+        if let Some(value) = value {
+            encode_pair(&name, value.as_bytes(), &mut result);
+        }
+    }
+
+    Response::new(stream, fastcgi::NULL_REQUEST_ID).record(fastcgi::GET_VALUES_RESULT, &result)
+}
+
+/// Skip a record the application does not understand, the connection goes on
+pub(crate) fn unknown_type(stream: &RefCell<dyn Transport + '_>, type_: u8) -> io::Result<()>
+{
+    warn!("Skip record of unknown type {}", type_);
+
+    Response::new(stream, fastcgi::NULL_REQUEST_ID).unknown_type(type_)
+}
+
+/// Names asked by a `GET_VALUES` record
+fn value_names(data: Vec<u8>) -> Vec<Vec<u8>>
+{
+    ParamFetcher::new(data).parse_param().into_keys().collect()
+}
+
+/// Append a name-value pair as `PARAMS` and `GET_VALUES_RESULT` records carry them
+fn encode_pair(name: &[u8], value: &[u8], data: &mut Vec<u8>)
+{
+    for len in &[name.len(), value.len()] {
+        if *len < 0x80 {
+            data.push(*len as u8);
+        } else {
+            let mut buf: [u8; 4] = [0; 4];
+            BigEndian::write_u32(&mut buf, *len as u32 | 0x8000_0000);
+            data.extend_from_slice(&buf);
+        }
+    }
+
+    data.extend_from_slice(name);
+    data.extend_from_slice(value);
+}
+
 /// Helper for split key-value param pairs
 struct ParamFetcher
 {
@@ -191,7 +372,7 @@ impl ParamFetcher
     fn new(data: Vec<u8>) -> ParamFetcher
     {
         ParamFetcher {
-            data: data,
+            data,
             pos: 0,
         }
     }
@@ -227,7 +408,7 @@ impl ParamFetcher
 
         if (length >> 7) == 1 {
 
-            self.data[self.pos] = self.data[self.pos] & 0x7F;
+            self.data[self.pos] &= 0x7F;
             length = BigEndian::read_u32(&self.data[self.pos..(self.pos + 4)]) as usize;
 
             self.pos += 4;
@@ -242,18 +423,17 @@ impl ParamFetcher
 
 
 /// HTTP status header
-const HTTP_STATUS: &'static str = "Status";
+const HTTP_STATUS: &str = "Status";
 /// HTTP line delimiter
-const HTTP_LINE: &'static str = "\r\n";
+const HTTP_LINE: &str = "\r\n";
 
-#[derive(Debug)]
 /// HTTP implementation of response
 pub struct Response<'sw>
 {
     id: u16,
     header: HashMap<Vec<u8>, Vec<u8>>,
     buf: Vec<u8>,
-    stream: &'sw TcpStream,
+    stream: &'sw RefCell<dyn Transport + 'sw>,
     pending: bool,
 }
 
@@ -286,7 +466,7 @@ impl<'sw> Response<'sw>
     }
 
     /// Constructor
-    pub(crate) fn new(stream: &'sw TcpStream, id: u16) -> Response
+    pub(crate) fn new(stream: &'sw RefCell<dyn Transport + 'sw>, id: u16) -> Response<'sw>
     {
         let mut header = HashMap::new();
         header.insert(Vec::from(HTTP_STATUS.as_bytes()),
@@ -294,10 +474,10 @@ impl<'sw> Response<'sw>
         );
 
         Response {
-            id: id,
-            header: header,
+            id,
+            header,
             buf: Vec::new(),
-            stream: stream,
+            stream,
             pending: false,
         }
     }
@@ -320,12 +500,39 @@ impl<'sw> Response<'sw>
         data
     }
 
+    /// Terminate request without response
+    pub(crate) fn end(&mut self, protocol_status: u8) -> io::Result<()>
+    {
+        let data = self.end_request(protocol_status);
+        self.stream.borrow_mut().write_all(&data)
+    }
+
+    /// Send a whole record of any type
+    pub(crate) fn record(&mut self, type_: u8, data: &[u8]) -> io::Result<()>
+    {
+        let mut record = self.record_header(type_, data.len() as u16);
+        record.extend_from_slice(data);
+        self.stream.borrow_mut().write_all(&record)
+    }
+
+    /// Tell the web server a record of `type_` was skipped
+    pub(crate) fn unknown_type(&mut self, type_: u8) -> io::Result<()>
+    {
+        let data = fastcgi::UnknownTypeBody {
+                       type_,
+                       reserved: [0; 7],
+                   }
+                   .write();
+
+        self.record(fastcgi::UNKNOWN_TYPE, &data)
+    }
+
     /// End request record
-    fn end_request(&self) -> Vec<u8>
+    fn end_request(&self, protocol_status: u8) -> Vec<u8>
     {
         let data = fastcgi::EndRequestBody {
                        app_status: 0,
-                       protocol_status: fastcgi::REQUEST_COMPLETE,
+                       protocol_status,
                        reserved: [0; 3],
                    }
                    .write();
@@ -341,7 +548,7 @@ impl<'sw> Response<'sw>
     {
         let header = fastcgi::Header {
             version: fastcgi::VERSION_1,
-            type_: type_,
+            type_,
             request_id: self.id,
             content_length: length,
             padding_length: 0,
@@ -351,24 +558,40 @@ impl<'sw> Response<'sw>
         header.write()
     }
 
-    fn send_header(&mut self)
+    fn send_header(&mut self) -> io::Result<()>
     {
         if !self.pending {
             for part in self.http_headers().chunks(fastcgi::MAX_LENGTH) {
-                let header = self.record_header(fastcgi::STDOUT, part.len() as u16);
-                self.stream.write(&header).expect("Send response headers");
-                self.stream.write(&part).expect("Send response headers");
+                // whole record in one write, other threads may write to the connection
+                let mut record = self.record_header(fastcgi::STDOUT, part.len() as u16);
+                record.extend_from_slice(part);
+                self.stream.borrow_mut().write_all(&record)?;
             }
 
             self.pending = true;
         }
+
+        Ok(())
+    }
+
+    fn send_chunk(&mut self, end: usize) -> io::Result<()>
+    {
+        let mut record = self.record_header(fastcgi::STDOUT, end as u16);
+        record.extend(self.buf.drain(..end));
+        self.stream.borrow_mut().write_all(&record)
     }
+}
 
-    fn send_chunk(&mut self, end: usize)
+impl<'sw> fmt::Debug for Response<'sw>
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
     {
-        let h = self.record_header(fastcgi::STDOUT, end as u16);
-        self.stream.write(&h).expect("Send response body");
-        self.stream.write(&self.buf.drain(..end).collect::<Vec<_>>()).expect("Send response body");
+        f.debug_struct("Response")
+            .field("id", &self.id)
+            .field("header", &self.header)
+            .field("buf", &self.buf)
+            .field("pending", &self.pending)
+            .finish()
     }
 }
 
@@ -377,10 +600,10 @@ impl<'sw> io::Write for Response<'sw>
 
     fn write(&mut self, buf: &[u8]) -> io::Result<usize>
     {
-        self.send_header();
+        self.send_header()?;
         self.buf.extend_from_slice(buf);
         while self.buf.len() > fastcgi::MAX_LENGTH {
-            self.send_chunk(fastcgi::MAX_LENGTH);
+            self.send_chunk(fastcgi::MAX_LENGTH)?;
         }
 
         Ok(buf.len())
@@ -390,17 +613,19 @@ impl<'sw> io::Write for Response<'sw>
     {
         let mut data: Vec<u8> = Vec::new();
 
-        self.send_header();
+        self.send_header()?;
 
-        // self a rest
+        // self a rest, empty record would terminate the stream
         let end = self.buf.len();
-        self.send_chunk(end);
+        if end > 0 {
+            self.send_chunk(end)?;
+        }
 
         // terminate record
         data.extend_from_slice(&self.record_header(fastcgi::STDOUT, 0));
-        data.extend_from_slice(&self.end_request());
+        data.extend_from_slice(&self.end_request(fastcgi::REQUEST_COMPLETE));
 
-        self.stream.write(&data).expect("Send end");
+        self.stream.borrow_mut().write_all(&data)?;
 
         Ok(())
     }
@@ -410,3 +635,29 @@ impl<'sw> io::Write for Response<'sw>
 
 
 
+
+#[cfg(test)]
+mod tests
+{
+    use super::{encode_pair, ParamFetcher};
+
+    #[test]
+    fn long_pairs()
+    {
+        let name = vec![b'n'; 0x80];
+        let value = vec![b'v'; 300];
+
+        let mut data = Vec::new();
+        encode_pair(&name, &value, &mut data);
+        assert_eq!(data[..8], [0x80, 0, 0, 0x80, 0x80, 0, 1, 0x2c]);
+        assert_eq!(data.len(), 8 + 0x80 + 300);
+
+        encode_pair(b"SHORT", b"", &mut data);
+        assert_eq!(data[8 + 0x80 + 300..], b"\x05\x00SHORT"[..]);
+
+        let params = ParamFetcher::new(data).parse_param();
+        assert_eq!(params.len(), 2);
+        assert_eq!(params[&name], value);
+        assert_eq!(params[&b"SHORT"[..]], b"");
+    }
+}
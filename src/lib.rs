@@ -1,100 +1,891 @@
 #![allow(dead_code)]
 //! This crate provides FastCGI client with supporting multithreaded socket listener and HTTP-instances multiplexed into a single connection.
+#[cfg(unix)]
+extern crate libc;
+#[macro_use]
+extern crate log;
+
 // object
+mod accept;
+mod authorizer;
+mod builder;
+mod connection;
+#[cfg(unix)]
+mod daemon;
 mod fastcgi;
 mod http;
+mod listener;
+mod pool;
+#[cfg(unix)]
+mod pm;
+#[cfg(unix)]
+mod prefork;
+#[cfg(unix)]
+mod privileges;
+#[cfg(unix)]
+mod signals;
+mod shutdown;
+mod status;
+#[cfg(unix)]
+mod systemd;
+#[cfg(unix)]
+mod upgrade;
+mod watchdog;
+
+pub use accept::AcceptErrorKind;
+pub use authorizer::{Access, Authorizer, AuthorizerHandler};
+pub use builder::{ClientBuilder, Limits, Overflow};
+pub use http::{Request, Response, Role};
+pub use shutdown::ShutdownHandle;
+pub use status::{RunningRequest, Status, StatusFormat, StatusHandler};
+#[cfg(unix)]
+pub use daemon::{Daemon, Pidfile};
+#[cfg(unix)]
+pub use listener::UnixSocket;
+#[cfg(unix)]
+pub use pm::ProcessManager;
+#[cfg(unix)]
+pub use signals::Signals;
+
+pub use listener::{ListenerId, ListenerStats};
 
-pub use http::{Request, Response};
+use accept::Backoff;
+use builder::Config;
+use connection::{Connections, Guarded};
+use listener::{Entry, Listener, Stream, Waker};
+use pool::Pool;
+#[cfg(unix)]
+use privileges::Privileges;
 
 // Data struct
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::iter::Iterator;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 // net / io
-use std::net::{TcpListener, TcpStream, ToSocketAddrs, Incoming};
-use std::io::Write;
-
-// Thread
-#[cfg(feature="spawn")]
+use std::net::{IpAddr, TcpListener, ToSocketAddrs};
+use std::io;
+use std::io::{Read, Write};
+use std::str;
+#[cfg(unix)]
+use std::path::Path;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
 use std::thread;
+use std::time::Duration;
 
+/// Socket listeners wrapper
+///
+/// Clones share the same listeners, so one of them can add or remove
+/// listeners while another one runs.
+#[derive(Clone)]
 pub struct Client
 {
-    listener: TcpListener,
+    inner: Arc<Inner>,
+}
+
+struct Inner
+{
+    listeners: Mutex<Vec<Arc<Entry>>>,
+    wakers: Mutex<Vec<Arc<Waker>>>,
+    next_id: AtomicUsize,
+    web_server_addrs: Mutex<Option<Vec<IpAddr>>>,
+    config: Config,
+    connections: Connections,
+    /// Transport connections from accept to close, see `ClientBuilder::max_conns`
+    open: AtomicUsize,
+    shutdown: AtomicBool,
+    /// Time given to connections in progress on shutdown
+    drain_timeout: Mutex<Duration>,
+    /// Previous process waiting for readiness after `upgrade`
+    #[cfg(unix)]
+    ready: Mutex<Option<UnixStream>>,
+    /// Worker processes of `run_prefork`
+    #[cfg(unix)]
+    scoreboard: Mutex<Option<Arc<pm::Scoreboard>>>,
+    /// Actions of `handle_signals`
+    #[cfg(unix)]
+    signals: Mutex<Option<Arc<Signals>>>,
+    /// Credentials of `ClientBuilder` not switched to yet
+    #[cfg(unix)]
+    privileges: Mutex<Option<Privileges>>,
+    /// `SIGHUP` seen under `run_prefork`, which replaces workers
+    #[cfg(unix)]
+    reload: AtomicBool,
+    /// `SIGUSR1` seen, `run_prefork` passes it to workers
+    #[cfg(unix)]
+    reopen: AtomicBool,
+    /// Bumped when log files are to be reopened
+    log_generation: AtomicUsize,
 }
 
-/// TcpListener wrapper
 impl Client
 {
+    /// Listen on TCP address, panics if it can not be bound
+    ///
+    /// Use `ClientBuilder` to handle errors and tune the connection options.
     pub fn new<A: ToSocketAddrs>(addr: A) -> Client
+    {
+        Self::from_listener(Listener::Tcp(TcpListener::bind(addr).expect("Bind address")))
+            .expect("Bind address")
+    }
+
+    /// Listen on the socket passed by the web server as `FCGI_LISTENSOCK_FILENO`
+    ///
+    /// Useful when the application is spawned by mod_fcgid, spawn-fcgi and alike.
+    #[cfg(unix)]
+    pub fn from_inherited() -> io::Result<Client>
+    {
+        Self::from_listener(Listener::from_fd(fastcgi::LISTENSOCK_FILENO as RawFd)?)
+    }
+
+    /// Listen on sockets passed by systemd socket activation
+    ///
+    /// Yields one `Client` per socket along with its `FileDescriptorName=`,
+    /// empty when the process was not socket-activated.
+    #[cfg(unix)]
+    pub fn from_systemd() -> io::Result<Vec<(String, Client)>>
+    {
+        let mut result = Vec::new();
+        for (name, fd) in systemd::listen_fds()? {
+            result.push((name, Self::from_listener(Listener::from_fd(fd)?)?));
+        }
+
+        Ok(result)
+    }
+
+    /// Listen on Unix domain socket with default options
+    #[cfg(unix)]
+    pub fn bind_unix<P: AsRef<Path>>(path: P) -> io::Result<Client>
+    {
+        Self::with_unix(&UnixSocket::new(path))
+    }
+
+    /// Listen on Unix domain socket
+    #[cfg(unix)]
+    pub fn with_unix(socket: &UnixSocket) -> io::Result<Client>
+    {
+        Self::from_listener(Listener::Unix(socket.bind()?))
+    }
+
+    /// Client configuration
+    pub fn builder() -> ClientBuilder
+    {
+        ClientBuilder::new()
+    }
+
+    fn from_listener(listener: Listener) -> io::Result<Client>
+    {
+        let client = Self::with_config(Config::default());
+        client.add_listener(listener)?;
+
+        Ok(client)
+    }
+
+    /// Client without listeners
+    pub(crate) fn with_config(config: Config) -> Client
     {
         Client {
-            listener: TcpListener::bind(addr).expect("Bind address"),
+            inner: Arc::new(Inner {
+                listeners: Mutex::new(Vec::new()),
+                wakers: Mutex::new(Vec::new()),
+                next_id: AtomicUsize::new(0),
+                web_server_addrs: Mutex::new(listener::web_server_addrs()),
+                config,
+                connections: Connections::default(),
+                open: AtomicUsize::new(0),
+                shutdown: AtomicBool::new(false),
+                drain_timeout: Mutex::new(Duration::from_secs(0)),
+                #[cfg(unix)]
+                ready: Mutex::new(None),
+                #[cfg(unix)]
+                scoreboard: Mutex::new(None),
+                #[cfg(unix)]
+                signals: Mutex::new(None),
+                #[cfg(unix)]
+                privileges: Mutex::new(None),
+                #[cfg(unix)]
+                reload: AtomicBool::new(false),
+                #[cfg(unix)]
+                reopen: AtomicBool::new(false),
+                log_generation: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Listen on one more TCP address
+    pub fn add_tcp<A: ToSocketAddrs>(&self, addr: A) -> io::Result<ListenerId>
+    {
+        self.add_listener(Listener::Tcp(TcpListener::bind(addr)?))
+    }
+
+    /// Listen on one more Unix domain socket
+    #[cfg(unix)]
+    pub fn add_unix(&self, socket: &UnixSocket) -> io::Result<ListenerId>
+    {
+        self.add_listener(Listener::Unix(socket.bind()?))
+    }
+
+    /// Move all listeners of `other` into this client
+    pub fn append(&self, other: Client) -> Vec<ListenerId>
+    {
+        let entries: Vec<_> = other.inner.listeners.lock().unwrap().drain(..).collect();
+        other.wake();
+
+        entries.into_iter()
+            .map(|e| self.insert(|id| e.try_clone_as(id).expect("Clone listener")))
+            .collect()
+    }
+
+    /// Stop listening, connections in progress are served to the end
+    pub fn remove_listener(&self, id: ListenerId) -> bool
+    {
+        let removed = {
+            let mut listeners = self.inner.listeners.lock().unwrap();
+            let len = listeners.len();
+            listeners.retain(|e| e.id != id);
+            len != listeners.len()
+        };
+        self.wake();
+
+        removed
+    }
+
+    /// Restrict TCP peers to the given addresses, `None` accepts everyone
+    ///
+    /// Defaults to `FCGI_WEB_SERVER_ADDRS` environment variable.
+    /// Unix domain socket peers are not checked, file permissions guard them.
+    pub fn web_server_addrs(&self, addrs: Option<Vec<IpAddr>>)
+    {
+        *self.inner.web_server_addrs.lock().unwrap() = addrs;
+    }
+
+    /// Counters of every listener
+    pub fn stats(&self) -> Vec<ListenerStats>
+    {
+        self.inner.listeners.lock().unwrap()
+            .iter()
+            .map(|e| e.stats())
+            .collect()
+    }
+
+    pub(crate) fn add_listener(&self, listener: Listener) -> io::Result<ListenerId>
+    {
+        self.add_sharded(listener, Vec::new())
+    }
+
+    /// Listener with `SO_REUSEPORT` sockets for worker threads
+    pub(crate) fn add_sharded(&self, listener: Listener, shards: Vec<Listener>) -> io::Result<ListenerId>
+    {
+        let entry = Entry::with_shards(ListenerId(0), listener, shards)?;
+
+        Ok(self.insert(|id| Entry { id, ..entry }))
+    }
+
+    fn insert<F: FnOnce(ListenerId) -> Entry>(&self, entry: F) -> ListenerId
+    {
+        let id = ListenerId(self.inner.next_id.fetch_add(1, Ordering::Relaxed));
+        self.inner.listeners.lock().unwrap().push(Arc::new(entry(id)));
+        self.wake();
+
+        id
+    }
+
+    /// Workers, counters and requests in progress
+    ///
+    /// Under `run_prefork` every worker process is reported.
+    pub fn status(&self) -> Status
+    {
+        let stats = self.stats();
+        let listen_queue = stats.iter()
+            .filter_map(|s| s.queue)
+            .fold(None, |sum, q| Some(sum.unwrap_or(0) + q));
+
+        #[cfg(unix)]
+        {
+            if let Some(ref board) = *self.inner.scoreboard.lock().unwrap() {
+                return board.status(listen_queue);
+            }
+        }
+
+        let connections = self.inner.connections.list();
+        let running: Vec<RunningRequest> = connections.iter()
+            .filter_map(|c| c.current.lock().unwrap().as_ref().map(|current| RunningRequest {
+                worker: current.thread.clone(),
+                request_uri: current.request_uri.clone(),
+                elapsed: current.started.elapsed(),
+            }))
+            .collect();
+        let workers = self.inner.config.workers;
+
+        Status {
+            workers,
+            active: running.len(),
+            idle: workers.saturating_sub(running.len()),
+            accepted: stats.iter().map(|s| s.accepted as u64).sum(),
+            requests: stats.iter().map(|s| s.requests as u64).sum(),
+            listen_queue,
+            running,
+        }
+    }
+
+    /// Serve `status` at `path`, other requests go to `handler`
+    ///
+    /// ```no_run
+    /// # struct Router;
+    /// # impl gfcgi::Handler for Router { fn process(&self, _: &mut gfcgi::Request, _: &mut gfcgi::Response) {} }
+    /// let client = gfcgi::Client::new("127.0.0.1:4128");
+    /// client.run(client.status_handler("/status", Router));
+    /// ```
+    pub fn status_handler<T: Handler>(&self, path: &str, handler: T) -> StatusHandler<T>
+    {
+        StatusHandler {
+            client: self.clone(),
+            path: path.to_owned(),
+            handler,
+        }
+    }
+
+    /// Handle to stop `run` from another thread
+    pub fn shutdown_handle(&self) -> ShutdownHandle
+    {
+        ShutdownHandle {
+            inner: self.inner.clone(),
+        }
+    }
+
+    /// Act on `SIGTERM`, `SIGQUIT`, `SIGHUP` and `SIGUSR1` as `signals` says
+    ///
+    /// Call it after `Daemon::start` and before `run`, which starts a thread
+    /// running the actions. No thread is started here, so it may precede
+    /// `run_prefork` as well, its master process runs them between forks.
+    /// Fails if handlers are installed already.
+    ///
+    /// ```no_run
+    /// # struct Router;
+    /// # impl gfcgi::Handler for Router { fn process(&self, _: &mut gfcgi::Request, _: &mut gfcgi::Response) {} }
+    /// let client = gfcgi::Client::new("127.0.0.1:4128");
+    /// client.handle_signals(gfcgi::Signals::new()
+    ///     .drain_timeout(std::time::Duration::from_secs(10))
+    ///     .on_reload(|| println!("reload")))
+    ///     .expect("install signal handlers");
+    /// client.run(Router);
+    /// ```
+    #[cfg(unix)]
+    pub fn handle_signals(&self, signals: &Signals) -> io::Result<()>
+    {
+        *self.inner.signals.lock().unwrap() = Some(Arc::new(signals.clone()));
+
+        signals::install()
+    }
+
+    /// Switch to the user, groups and root directory set on `ClientBuilder`
+    ///
+    /// `run` and `run_prefork` call it first, so files of `Daemon::start` such as
+    /// the pidfile and logs are opened with the privileges of the starting user.
+    /// Call it earlier to run more code unprivileged. Does nothing once it succeeded.
+    #[cfg(unix)]
+    pub fn drop_privileges(&self) -> io::Result<()>
+    {
+        let mut privileges = self.inner.privileges.lock().unwrap();
+        if let Some(ref privileges) = *privileges {
+            privileges.apply()?;
+        }
+        *privileges = None;
+
+        Ok(())
+    }
+
+    /// Replace the process by a new copy of the executable without closing listeners
+    ///
+    /// The new process is started with the same arguments and takes over the
+    /// listening sockets when built with `ClientBuilder`, it reports readiness
+    /// on its first `run`. Then this client drains as on `ShutdownHandle::shutdown`.
+    /// On failure the new process is killed and this one keeps serving.
+    /// `SO_REUSEPORT` sockets are passed as well, connections queued there are not lost.
+    #[cfg(unix)]
+    pub fn upgrade(&self, ready_timeout: Duration, drain_timeout: Duration) -> io::Result<u32>
+    {
+        let entries = self.inner.listeners.lock().unwrap().clone();
+        // `SO_REUSEPORT` sockets shut down by `run_prefork` are left out
+        let fds: Vec<Vec<RawFd>> = entries.iter()
+            .map(|e| Some(&e.listener).into_iter()
+                .chain(e.shards.iter().filter(|s| s.is_listening()))
+                .map(|l| l.as_raw_fd())
+                .collect())
+            .collect();
+
+        let pid = upgrade::spawn(&fds, ready_timeout)?;
+        info!("Process {} is ready, draining", pid);
+
+        self.shutdown_handle().shutdown(drain_timeout);
+
+        Ok(pid)
+    }
+
+    /// Let running threads pick up listener changes
+    fn wake(&self)
+    {
+        for waker in self.inner.wakers.lock().unwrap().iter() {
+            waker.wake();
         }
     }
 
-    /// Run thread
     /// Accept `Handler` as callback
-    #[cfg(feature="spawn")]
-    pub fn run<T: Handler + Send + Clone + 'static>(&self, handler: T) -> std::thread::JoinHandle<()>
+    ///
+    /// The calling thread accepts connections and hands them to `ClientBuilder::workers`
+    /// threads, each serves one connection at a time. With `ClientBuilder::reuseport`
+    /// every worker accepts on its own socket instead. Returns after shutdown
+    /// and every worker is done, see `shutdown_handle`.
+    pub fn run<T: Handler + Send + Sync + 'static>(&self, handler: T)
+    {
+        #[cfg(unix)]
+        self.drop_privileges().expect("Drop privileges");
+
+        #[cfg(unix)]
+        if let Err(e) = signals::spawn(self.inner.clone()) {
+            error!("Start signal thread: {}", e);
+        }
+
+        let config = &self.inner.config;
+        if config.reuseport {
+            return self.run_sharded(handler);
+        }
+
+        let client = self.clone();
+        let pool = Pool::new(config.workers, config.queue, move |entry, stream| client.serve(entry, stream, &handler))
+            .expect("Start workers");
+
+        let watchdog = self.watch();
+
+        self.listen(0, |entry, stream| pool.execute((entry, stream)));
+        pool.join();
+
+        if let Some(watchdog) = watchdog {
+            let _ = watchdog.join();
+        }
+    }
+
+    /// Every worker thread accepts on its own `SO_REUSEPORT` socket and serves in place
+    fn run_sharded<T: Handler + Send + Sync + 'static>(&self, handler: T)
+    {
+        let handler = Arc::new(handler);
+        let watchdog = self.watch();
+
+        let workers: Vec<_> = (0..self.inner.config.workers)
+            .map(|shard| {
+                let client = self.clone();
+                let handler = handler.clone();
+
+                thread::Builder::new()
+                    .name(format!("gfcgi-worker-{}", shard))
+                    .spawn(move || client.listen(shard, |entry, stream| {
+                        pool::guard(|| client.serve(&entry, stream, &*handler))
+                    }))
+                    .expect("Start workers")
+            })
+            .collect();
+
+        for worker in workers {
+            let _ = worker.join();
+        }
+
+        if let Some(watchdog) = watchdog {
+            let _ = watchdog.join();
+        }
+    }
+
+    /// Accept `Handler` as callback in forked processes
+    ///
+    /// Each process accepts on the shared listeners and serves one connection
+    /// at a time in a single thread, so the handler needs neither `Send` nor `Sync`.
+    /// This process forks, stops and restarts them as `ClientBuilder::process_manager`
+    /// says, and replaces those reaching `ClientBuilder::max_requests`. Call it
+    /// before starting other threads, `handle_signals` starts none. Returns after
+    /// shutdown and every process is done, listener counters of `stats` are not updated.
+    #[cfg(unix)]
+    pub fn run_prefork<T: Handler>(&self, handler: T) -> io::Result<()>
     {
-        let listener = self.listener.try_clone().expect("Clone listener");
-        let handler = handler.clone();
+        self.drop_privileges()?;
 
-        thread::spawn(move || {
-            Self::listen(listener.incoming(), handler);
+        prefork::run(self, self.inner.config.process_manager, |client, board, slot| {
+            let handler = pm::Tracked {
+                board,
+                slot,
+                handler: &handler,
+                max_requests: client.inner.config.max_requests,
+                shutdown: client.shutdown_handle(),
+            };
+            client.watch();
+            client.listen(0, |entry, stream| {
+                board.totals().accepted.fetch_add(1, Ordering::Relaxed);
+                client.serve(&entry, stream, &handler)
+            })
         })
     }
 
-    /// Accept `Handler` as callback
-    #[cfg(not(feature="spawn"))]
-    pub fn run<T: Handler>(&self, handler: T)
+    /// Start the watchdog thread if request timeout or slowlog is set
+    fn watch(&self) -> Option<thread::JoinHandle<()>>
     {
-        Self::listen(self.listener.incoming(), handler);
+        let config = &self.inner.config;
+        if config.request_timeout.is_none() && config.slowlog.is_none() {
+            return None;
+        }
+
+        let inner = self.inner.clone();
+        let spawned = thread::Builder::new()
+            .name("gfcgi-watchdog".to_owned())
+            .spawn(move || watchdog::run(&inner));
+
+        match spawned {
+            Ok(watchdog) => Some(watchdog),
+            Err(e) => {
+                error!("Start watchdog: {}", e);
+                None
+            }
+        }
     }
 
-    fn listen<T: Handler>(incoming: Incoming, handler: T)
+    /// Accept connections on socket `shard` of every listener until shutdown, `dispatch` serves them
+    fn listen<F: FnMut(Arc<Entry>, Stream)>(&self, shard: usize, mut dispatch: F)
     {
-        for stream in incoming {
-            match stream {
-                Ok(stream) => {
-                    let reader = StreamSyntax::new(&stream);
-                    for pair in reader {
+        let waker = Arc::new(Waker::new().expect("Create waker"));
+        self.inner.wakers.lock().unwrap().push(waker.clone());
 
-                        // call handler
-                        let (mut request, mut response) = pair;
-                        handler.process(&mut request, &mut response);
+        #[cfg(unix)]
+        if let Some(ready) = self.inner.ready.lock().unwrap().take() {
+            upgrade::notify(ready);
+        }
+
+        let mut backoff = Backoff::new();
+        while !self.inner.shutdown.load(Ordering::SeqCst) {
+            // at the limit only a closed connection wakes up
+            let entries = if self.is_full() && self.inner.config.overflow == Overflow::Wait {
+                Vec::new()
+            } else {
+                self.inner.listeners.lock().unwrap().clone()
+            };
+
+            let ready = match listener::poll(&entries, &waker, shard) {
+                Ok(ready) => ready,
+                Err(e) => {
+                    error!("Poll listeners: {}", e);
+                    backoff.wait();
+                    continue;
+                }
+            };
+            if self.inner.shutdown.load(Ordering::SeqCst) {
+                break;
+            }
 
-                        response.flush().unwrap();
+            for entry in ready {
+                let reserved = self.reserve();
+                if !reserved && self.inner.config.overflow == Overflow::Wait {
+                    break;
+                }
+
+                match entry.shard(shard).accept() {
+                    Ok(ref stream) if !self.is_allowed(stream) => {
+                        entry.rejected.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Ok(_) if !reserved => {
+                        warn!("Close connection on {}: {} connections open", entry.listener.address(), self.inner.open.load(Ordering::SeqCst));
+                        entry.rejected.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Ok(stream) => {
+                        backoff.reset();
+                        // released when served
+                        dispatch(entry.clone(), stream);
+                        continue;
                     }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                    Err(e) => self.accept_error(&entry, &e, &mut backoff),
                 }
-                Err(e) => panic!("{}", e),
+
+                if reserved {
+                    self.release();
+                }
+            }
+        }
+
+        self.inner.wakers.lock().unwrap().retain(|w| !Arc::ptr_eq(w, &waker));
+    }
+
+    /// Report failed accept, wait if retrying right away is pointless
+    fn accept_error(&self, entry: &Entry, e: &io::Error, backoff: &mut Backoff)
+    {
+        let kind = AcceptErrorKind::of(e);
+
+        entry.errors.fetch_add(1, Ordering::Relaxed);
+        warn!("Accept on {}: {} ({:?})", entry.listener.address(), e, kind);
+
+        if let Some(ref hook) = self.inner.config.accept_hook {
+            (hook.0)(e, kind);
+        }
+
+        if kind != AcceptErrorKind::Connection {
+            backoff.wait();
+        }
+    }
+
+    /// Connections served at once, advertised as `FCGI_MAX_CONNS`
+    fn max_conns(&self) -> usize
+    {
+        let config = &self.inner.config;
+
+        // one connection per process
+        #[cfg(unix)]
+        {
+            if self.inner.scoreboard.lock().unwrap().is_some() {
+                return config.process_manager.max_children();
+            }
+        }
+
+        config.max_conns.unwrap_or(config.workers + config.queue)
+    }
+
+    fn is_full(&self) -> bool
+    {
+        self.inner.config.max_conns.is_some_and(|max| self.inner.open.load(Ordering::SeqCst) >= max)
+    }
+
+    /// Count a connection about to be accepted, `false` at `max_conns`
+    fn reserve(&self) -> bool
+    {
+        let max = match self.inner.config.max_conns {
+            Some(max) => max,
+            None => {
+                self.inner.open.fetch_add(1, Ordering::SeqCst);
+                return true;
+            }
+        };
+
+        let mut open = self.inner.open.load(Ordering::SeqCst);
+        while open < max {
+            match self.inner.open.compare_exchange(open, open + 1, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return true,
+                Err(current) => open = current,
             }
         }
+
+        false
+    }
+
+    /// Connection closed, listeners waiting at `max_conns` go on
+    fn release(&self)
+    {
+        let open = self.inner.open.fetch_sub(1, Ordering::SeqCst);
+        if self.inner.config.max_conns.is_some_and(|max| open >= max) {
+            self.wake();
+        }
+    }
+
+    /// Check peer against `FCGI_WEB_SERVER_ADDRS`
+    fn is_allowed(&self, stream: &Stream) -> bool
+    {
+        let peer = match stream.peer_ip() {
+            Some(peer) => peer,
+            None => return true,
+        };
+
+        match *self.inner.web_server_addrs.lock().unwrap() {
+            Some(ref addrs) if !addrs.contains(&peer) => {
+                warn!("Reject connection from {}: not listed in {}", peer, fastcgi::WEB_SERVER_ADDRS);
+                false
+            }
+            _ => true,
+        }
+    }
+
+    fn serve<T: Handler>(&self, entry: &Entry, stream: Stream, handler: &T)
+    {
+        let config = &self.inner.config;
+
+        let _active = entry.activate();
+        let _open = Open(self);
+
+        if let Err(e) = stream.set_write_timeout(config.write_timeout)
+            .and_then(|_| stream.set_nodelay(config.nodelay)) {
+            warn!("Set connection options: {}", e);
+        }
+
+        let registered = match self.inner.connections.register(&stream) {
+            Ok(registered) => registered,
+            Err(e) => {
+                warn!("Register connection: {}", e);
+                return;
+            }
+        };
+        let connection = &registered.connection;
+
+        let stream = RefCell::new(Guarded::new(stream, connection.clone()));
+        let mut reader = StreamSyntax::with_limits(&stream, config.limits);
+        reader.set_max_conns(self.max_conns());
+        while !self.inner.shutdown.load(Ordering::SeqCst) {
+            // wait for the next request
+            let idle_timeout = config.idle_timeout.or(config.read_timeout);
+            if let Err(e) = stream.borrow().get_ref().set_read_timeout(idle_timeout) {
+                warn!("Set read timeout: {}", e);
+            }
+
+            let (mut request, mut response) = match reader.next() {
+                Some(pair) => pair,
+                None => break,
+            };
+
+            match accept_role(handler, &request, &mut response) {
+                Ok(true) => (),
+                Ok(false) => continue,
+                Err(e) => {
+                    warn!("Send response: {}", e);
+                    break;
+                }
+            }
+
+            connection.begin(&request);
+
+            if let Err(e) = stream.borrow().get_ref().set_read_timeout(config.read_timeout) {
+                warn!("Set read timeout: {}", e);
+            }
+
+            // call handler
+            handler.process(&mut request, &mut response);
+
+            let sent = response.flush();
+            if !connection.end() {
+                // the watchdog answered and closed the connection
+                break;
+            }
+
+            if let Err(e) = sent {
+                warn!("Send response: {}", e);
+                break;
+            }
+            entry.requests.fetch_add(1, Ordering::Relaxed);
+
+            if let Err(e) = request.discard() {
+                warn!("Skip request body: {}", e);
+                break;
+            }
+        }
+
+        // multiplexed requests not yet handled
+        if let Err(e) = reader.end_pending(fastcgi::OVERLOADED) {
+            warn!("End pending requests: {}", e);
+        }
+
+        // records left unread: a body not read, rejected or aborted requests
+        stream.borrow_mut().get_mut().linger();
+    }
+}
+
+/// End the request with `UNKNOWN_ROLE` unless `handler` takes its role, `false` then
+fn accept_role<T: Handler>(handler: &T, request: &Request, response: &mut Response) -> io::Result<bool>
+{
+    if handler.supports(request.role()) {
+        return Ok(true);
+    }
+
+    warn!("Request {} in unsupported role {:?}", request.get_id(), request.role());
+    response.end(fastcgi::UNKNOWN_ROLE)?;
+
+    Ok(false)
+}
+
+/// Accepted connection counted against `max_conns` until dropped
+struct Open<'c>(&'c Client);
+
+impl<'c> Drop for Open<'c>
+{
+    fn drop(&mut self)
+    {
+        self.0.release();
     }
 }
 
+/// Duplex byte stream carrying FastCGI records
+///
+/// Implemented for anything readable and writable: sockets, pipes, TLS streams or in-memory buffers.
+pub trait Transport: Read + Write {}
+
+impl<T: Read + Write + ?Sized> Transport for T {}
+
 /// HTTP request / response pairs
 pub type HttpPair<'s> = (Request<'s>, Response<'s>);
 
 /// FasctCGI request parser
+///
+/// Drive the protocol over a custom transport:
+///
+/// ```no_run
+/// # use std::cell::RefCell;
+/// # use std::io::{Read, Write};
+/// # fn serve<H: gfcgi::Handler>(socket: std::net::TcpStream, handler: H) {
+/// let stream = RefCell::new(socket);
+/// for (mut request, mut response) in gfcgi::StreamSyntax::new(&stream) {
+///     handler.process(&mut request, &mut response);
+///     response.flush().expect("send response");
+/// }
+/// # }
+/// ```
 pub struct StreamSyntax<'s>
 {
-    born: bool,
+    /// A request without `KEEP_CONN` flag came, stop after pending ones
+    closing: bool,
     pair: HashMap<u16, HttpPair<'s>>,
-    stream: &'s TcpStream,
+    stream: &'s RefCell<dyn Transport + 's>,
+    limits: Limits,
+    /// Answered to `GET_VALUES`, not told when unknown
+    max_conns: Option<usize>,
 }
 
 impl<'s> StreamSyntax<'s>
 {
-    fn new(stream: &'s TcpStream) -> StreamSyntax
+    pub fn new<T: Transport + 's>(stream: &'s RefCell<T>) -> StreamSyntax<'s>
+    {
+        Self::with_limits(stream, Limits::default())
+    }
+
+    /// Requests exceeding limits are ended with `OVERLOADED` status
+    pub fn with_limits<T: Transport + 's>(stream: &'s RefCell<T>, limits: Limits) -> StreamSyntax<'s>
     {
         StreamSyntax {
-            born: true,
+            closing: false,
             pair: HashMap::new(),
-            stream: stream,
+            stream,
+            limits,
+            max_conns: None,
         }
     }
+
+    /// Connections the application serves at once, as told to the web server
+    pub(crate) fn set_max_conns(&mut self, max_conns: usize)
+    {
+        self.max_conns = Some(max_conns);
+    }
+
+    /// End every request which is not yielded yet
+    pub(crate) fn end_pending(&mut self, protocol_status: u8) -> io::Result<()>
+    {
+        for (_, (_, mut response)) in self.pair.drain() {
+            response.end(protocol_status)?;
+        }
+
+        Ok(())
+    }
+
+    /// Refuse the request, its records are skipped until the id is begun again
+    fn reject(&mut self, id: u16, keep_conn: bool) -> io::Result<()>
+    {
+        self.pair.remove(&id);
+        self.closing |= !keep_conn;
+
+        Response::new(self.stream, id).end(fastcgi::OVERLOADED)
+    }
 }
 
 /// Iterator implementation
@@ -105,29 +896,61 @@ impl<'s> Iterator for StreamSyntax<'s>
     /// Yield HTTP request / response
     fn next(&mut self) -> Option<Self::Item>
     {
-        while !self.pair.is_empty() || self.born {
-            let h = Request::fcgi_header(self.stream);
-            let body = Request::fcgi_body(self.stream, &h);
+        while !self.closing || !self.pair.is_empty() {
+            // peer closed connection or timed out
+            let h = Request::fcgi_header(self.stream).ok()?;
+            let body = Request::fcgi_body(self.stream, &h).ok()?;
+            let id = h.request_id;
+
+            if id == fastcgi::NULL_REQUEST_ID {
+                http::management(self.stream, &h, body, self.max_conns).ok()?;
+                continue;
+            }
+
+            if !http::is_request_record(h.type_) {
+                http::unknown_type(self.stream, h.type_).ok()?;
+                continue;
+            }
+
+            if h.type_ == fastcgi::BEGIN_REQUEST {
+                let request = Request::new(self.stream, id, self.limits, self.max_conns).begin(body);
+
+                if !self.pair.contains_key(&id) && self.pair.len() >= self.limits.max_reqs {
+                    warn!("Reject request {}: over {} concurrent requests", id, self.limits.max_reqs);
+                    let keep_conn = request.keep_conn();
+                    self.reject(id, keep_conn).ok()?;
+                    continue;
+                }
+
+                self.pair.insert(id, (request, Response::new(self.stream, id)));
+                continue;
+            }
 
-            self.pair.entry(h.request_id)
-                .or_insert((
-                    Request::new(self.stream, h.request_id),
-                    Response::new(self.stream, h.request_id),
-                ));
+            // rest of a request answered, rejected or never begun
+            if !self.pair.contains_key(&id) {
+                continue;
+            }
 
             match h.type_ {
                 fastcgi::ABORT_REQUEST => {
-                    self.pair.remove(&h.request_id).unwrap()
-                        .1.flush()
-                        .expect("Send end request on abort")
+                    let (request, mut response) = self.pair.remove(&id).unwrap();
+                    self.closing |= !request.keep_conn();
+                    response.flush().ok()?
                 }
                 fastcgi::PARAMS if h.content_length == 0 => {
-                    self.born = false;
-                    return self.pair.remove(&h.request_id);
+                    let pair = self.pair.remove(&id).unwrap();
+                    self.closing |= !pair.0.keep_conn();
+                    return Some(pair);
                 }
                 _ => {
-                    self.pair.get_mut(&h.request_id).unwrap()
-                        .0.fcgi_record(h, body)
+                    let request = &mut self.pair.get_mut(&id).unwrap().0;
+                    let result = request.fcgi_record(h, body);
+                    let keep_conn = request.keep_conn();
+
+                    if let Err(e) = result {
+                        warn!("Reject request {}: {}", id, e);
+                        self.reject(id, keep_conn).ok()?;
+                    }
                 }
             }
         }
@@ -141,9 +964,424 @@ pub trait Handler
 {
     /// Run HTTP-request handling
     fn process(&self, request: &mut Request, response: &mut Response);
+
+    /// Requests of other roles are ended with `UNKNOWN_ROLE` status
+    fn supports(&self, role: Role) -> bool
+    {
+        role == Role::Responder
+    }
 }
 
+#[cfg(test)]
+mod tests
+{
+    use std::cell::{Cell, RefCell};
+    use std::io;
+    use std::io::{Cursor, Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+    use std::time::Duration;
+
+    use fastcgi;
+    use fastcgi::Readable;
+    use {accept_role, Access, Authorizer, AuthorizerHandler, Client, Handler, Limits, Request, Response, StreamSyntax};
+
+    /// Records of the web server in, records of the application out
+    struct Duplex
+    {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Duplex
+    {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>
+        {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Duplex
+    {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize>
+        {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()>
+        {
+            Ok(())
+        }
+    }
+
+    /// Answers with the body it read unless `NOREAD` is set, read errors are kept
+    #[derive(Default)]
+    struct Echo
+    {
+        error: Cell<Option<io::ErrorKind>>,
+    }
+
+    impl Handler for Echo
+    {
+        fn process(&self, request: &mut Request, response: &mut Response)
+        {
+            let mut body = Vec::new();
+            if request.header(b"NOREAD").is_none() {
+                if let Err(e) = request.read_to_end(&mut body) {
+                    self.error.set(Some(e.kind()));
+                }
+            }
+
+            response.status(200);
+            response.write_all(&body).unwrap();
+        }
+    }
+
+    /// Answers without reading the body
+    struct Hello;
+
+    impl Handler for Hello
+    {
+        fn process(&self, _: &mut Request, response: &mut Response)
+        {
+            response.status(200);
+            response.write_all(b"hello").unwrap();
+        }
+    }
+
+    struct Token;
+
+    impl Authorizer for Token
+    {
+        fn authorize(&self, request: &Request) -> Access
+        {
+            match request.header_utf8(b"HTTP_AUTHORIZATION") {
+                Some("secret") => Access::Allow {
+                    variables: vec![("USER".to_owned(), "admin".to_owned())],
+                },
+                _ => Access::Deny {
+                    status: 401,
+                    headers: Vec::new(),
+                    body: b"denied".to_vec(),
+                },
+            }
+        }
+    }
+
+    fn record(type_: u8, id: u16, content: &[u8]) -> Vec<u8>
+    {
+        let header = fastcgi::Header {
+            version: fastcgi::VERSION_1,
+            type_,
+            request_id: id,
+            content_length: content.len() as u16,
+            padding_length: 0,
+            reserved: [0; 1],
+        };
+
+        let mut data = fastcgi::Writable::write(&header);
+        data.extend_from_slice(content);
+        data
+    }
+
+    /// Short name-value pair
+    fn pair(name: &[u8], value: &[u8]) -> Vec<u8>
+    {
+        let mut data = vec![name.len() as u8, value.len() as u8];
+        data.extend_from_slice(name);
+        data.extend_from_slice(value);
+        data
+    }
+
+    fn begin(id: u16, role: u8, keep_conn: bool) -> Vec<u8>
+    {
+        let flags = if keep_conn { fastcgi::KEEP_CONN } else { 0 };
+        record(fastcgi::BEGIN_REQUEST, id, &[0, role, flags, 0, 0, 0, 0, 0])
+    }
+
+    /// Whole request of `role` with `params` and `body`
+    fn request(id: u16, role: u8, keep_conn: bool, params: &[(&[u8], &[u8])], body: &[u8]) -> Vec<u8>
+    {
+        let params: Vec<u8> = params.iter().flat_map(|&(name, value)| pair(name, value)).collect();
+
+        let mut data = begin(id, role, keep_conn);
+        if !params.is_empty() {
+            data.extend(record(fastcgi::PARAMS, id, &params));
+        }
+        data.extend(record(fastcgi::PARAMS, id, b""));
+        if !body.is_empty() {
+            data.extend(record(fastcgi::STDIN, id, body));
+        }
+        data.extend(record(fastcgi::STDIN, id, b""));
+        data
+    }
+
+    /// Serve `input` as `Client::serve` does, without timeouts
+    fn serve<T: Handler>(input: Vec<u8>, limits: Limits, handler: &T) -> Vec<(u8, u16, Vec<u8>)>
+    {
+        let stream = RefCell::new(Duplex {
+            input: Cursor::new(input),
+            output: Vec::new(),
+        });
+
+        {
+            let mut reader = StreamSyntax::with_limits(&stream, limits);
+            reader.set_max_conns(8);
+            for (mut request, mut response) in reader.by_ref() {
+                if !accept_role(handler, &request, &mut response).unwrap() {
+                    continue;
+                }
+                handler.process(&mut request, &mut response);
+                response.flush().unwrap();
+                request.discard().unwrap();
+            }
+            reader.end_pending(fastcgi::OVERLOADED).unwrap();
+        }
+
+        records(&stream.into_inner().output)
+    }
+
+    /// Type, request id and content of each record
+    fn records(output: &[u8]) -> Vec<(u8, u16, Vec<u8>)>
+    {
+        let mut records = Vec::new();
+        let mut pos = 0;
+        while pos < output.len() {
+            let h = fastcgi::Header::read(&output[pos..pos + fastcgi::HEADER_LEN]);
+            pos += fastcgi::HEADER_LEN;
+            records.push((h.type_, h.request_id, output[pos..pos + h.content_length as usize].to_vec()));
+            pos += h.content_length as usize + h.padding_length as usize;
+        }
+        records
+    }
+
+    /// Protocol status of every `END_REQUEST`
+    fn ends(records: &[(u8, u16, Vec<u8>)]) -> Vec<(u16, u8)>
+    {
+        records.iter()
+            .filter(|r| r.0 == fastcgi::END_REQUEST)
+            .map(|r| (r.1, fastcgi::EndRequestBody::read(&r.2).protocol_status))
+            .collect()
+    }
+
+    /// `STDOUT` of request `id` as one string
+    fn stdout(records: &[(u8, u16, Vec<u8>)], id: u16) -> String
+    {
+        let data: Vec<u8> = records.iter()
+            .filter(|r| r.0 == fastcgi::STDOUT && r.1 == id)
+            .flat_map(|r| r.2.clone())
+            .collect();
+
+        String::from_utf8(data).unwrap()
+    }
+
+    #[test]
+    fn responder()
+    {
+        let records = serve(request(1, fastcgi::RESPONDER, false, &[(b"HTTP_HOST", b"example")], b"body"), Limits::default(), &Echo::default());
+
+        assert_eq!(ends(&records), vec![(1, fastcgi::REQUEST_COMPLETE)]);
+
+        let stdout = stdout(&records, 1);
+        assert!(stdout.contains("Status:200\r\n"));
+        assert!(stdout.ends_with("\r\n\r\nbody"));
+    }
+
+    #[test]
+    fn keep_conn_skips_unread_body()
+    {
+        let mut input = request(1, fastcgi::RESPONDER, true, &[(b"NOREAD", b"1")], b"OLDBODY");
+        input.extend(request(1, fastcgi::RESPONDER, false, &[], b"NEW"));
+
+        let records = serve(input, Limits::default(), &Echo::default());
+
+        assert_eq!(ends(&records), vec![(1, fastcgi::REQUEST_COMPLETE), (1, fastcgi::REQUEST_COMPLETE)]);
+        assert!(stdout(&records, 1).ends_with("\r\n\r\nNEW"));
+    }
+
+    #[test]
+    fn closing_request_ends_connection()
+    {
+        let mut input = request(1, fastcgi::RESPONDER, false, &[], b"first");
+        input.extend(request(2, fastcgi::RESPONDER, false, &[], b"second"));
+
+        let records = serve(input, Limits::default(), &Echo::default());
+
+        assert_eq!(ends(&records), vec![(1, fastcgi::REQUEST_COMPLETE)]);
+    }
+
+    #[test]
+    fn unread_body_does_not_reset_connection()
+    {
+        let client = Client::new("127.0.0.1:0");
+        let address = client.stats()[0].address.clone();
+        let server = {
+            let client = client.clone();
+            thread::spawn(move || client.run(Hello))
+        };
+
+        // the body stays in socket buffers, the handler answers without reading it
+        let mut socket = TcpStream::connect(&address[..]).unwrap();
+        socket.write_all(&request(1, fastcgi::RESPONDER, false, &[], &[b'x'; 0xffff])).unwrap();
+
+        let mut output = Vec::new();
+        socket.read_to_end(&mut output).unwrap();
+        let records = records(&output);
+        assert_eq!(ends(&records), vec![(1, fastcgi::REQUEST_COMPLETE)]);
+        assert!(stdout(&records, 1).ends_with("hello"));
+
+        client.shutdown_handle().shutdown(Duration::from_secs(1));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn get_values()
+    {
+        let mut names = pair(b"FCGI_MAX_CONNS", b"");
+        names.extend(pair(b"FCGI_MAX_REQS", b""));
+        names.extend(pair(b"FCGI_MPXS_CONNS", b""));
+        names.extend(pair(b"OTHER", b""));
+
+        // before a request and between its body records
+        let mut input = record(fastcgi::GET_VALUES, fastcgi::NULL_REQUEST_ID, &names);
+        let mut body = request(1, fastcgi::RESPONDER, false, &[], b"body");
+        let end = body.len() - fastcgi::HEADER_LEN;
+        body.splice(end..end, record(fastcgi::GET_VALUES, fastcgi::NULL_REQUEST_ID, &names));
+        input.extend(body);
+
+        let records = serve(input, Limits::default(), &Echo::default());
+
+        let results: Vec<_> = records.iter().filter(|r| r.0 == fastcgi::GET_VALUES_RESULT).collect();
+        assert_eq!(results.len(), 2);
+        for result in results {
+            assert_eq!(result.1, fastcgi::NULL_REQUEST_ID);
 
+            let mut expected = vec![
+                pair(b"FCGI_MAX_CONNS", b"8"),
+                pair(b"FCGI_MAX_REQS", b"8"),
+                pair(b"FCGI_MPXS_CONNS", b"0"),
+            ];
+            // pairs come in any order
+            let mut data = result.2.clone();
+            while !data.is_empty() {
+                let len = 2 + data[0] as usize + data[1] as usize;
+                let position = expected.iter().position(|p| p[..] == data[..len]).expect("known value");
+                expected.remove(position);
+                data.drain(..len);
+            }
+            assert!(expected.is_empty());
+        }
+        assert!(stdout(&records, 1).ends_with("body"));
+    }
+
+    #[test]
+    fn unknown_type()
+    {
+        let mut input = record(12, fastcgi::NULL_REQUEST_ID, b"");
+        input.extend(record(12, 1, b"xx"));
+        input.extend(request(1, fastcgi::RESPONDER, false, &[], b"body"));
+
+        let records = serve(input, Limits::default(), &Echo::default());
 
+        let unknown: Vec<_> = records.iter().filter(|r| r.0 == fastcgi::UNKNOWN_TYPE).collect();
+        assert_eq!(unknown.len(), 2);
+        for record in unknown {
+            assert_eq!(record.1, fastcgi::NULL_REQUEST_ID);
+            assert_eq!(fastcgi::UnknownTypeBody::read(&record.2).type_, 12);
+        }
+        assert_eq!(ends(&records), vec![(1, fastcgi::REQUEST_COMPLETE)]);
+    }
+
+    #[test]
+    fn data_is_skipped()
+    {
+        let mut input = request(1, fastcgi::RESPONDER, true, &[], b"body");
+        // between body records and after them
+        let end = input.len() - fastcgi::HEADER_LEN;
+        input.splice(end..end, record(fastcgi::DATA, 1, b"data"));
+        input.extend(record(fastcgi::DATA, 1, b"data"));
+        input.extend(record(fastcgi::DATA, 1, b""));
+        input.extend(request(2, fastcgi::RESPONDER, false, &[], b"next"));
+
+        let records = serve(input, Limits::default(), &Echo::default());
 
+        assert!(records.iter().all(|r| r.0 != fastcgi::UNKNOWN_TYPE));
+        assert_eq!(ends(&records), vec![(1, fastcgi::REQUEST_COMPLETE), (2, fastcgi::REQUEST_COMPLETE)]);
+        assert!(stdout(&records, 1).ends_with("\r\n\r\nbody"));
+        assert!(stdout(&records, 2).ends_with("next"));
+    }
 
+    #[test]
+    fn max_reqs()
+    {
+        let limits = Limits {
+            max_reqs: 1,
+            ..Limits::default()
+        };
+        let mut input = begin(1, fastcgi::RESPONDER, true);
+        input.extend(begin(2, fastcgi::RESPONDER, true));
+        input.extend(request(1, fastcgi::RESPONDER, false, &[], b""));
+
+        let records = serve(input, limits, &Echo::default());
+
+        assert_eq!(ends(&records), vec![(2, fastcgi::OVERLOADED), (1, fastcgi::REQUEST_COMPLETE)]);
+    }
+
+    #[test]
+    fn max_params()
+    {
+        let limits = Limits {
+            max_params: 16,
+            ..Limits::default()
+        };
+        let mut input = request(1, fastcgi::RESPONDER, true, &[(b"REQUEST_URI", b"/a/long/path")], b"");
+        input.extend(request(1, fastcgi::RESPONDER, false, &[(b"A", b"b")], b""));
+
+        let records = serve(input, limits, &Echo::default());
+
+        assert_eq!(ends(&records), vec![(1, fastcgi::OVERLOADED), (1, fastcgi::REQUEST_COMPLETE)]);
+    }
+
+    #[test]
+    fn max_body()
+    {
+        let limits = Limits {
+            max_body: 4,
+            ..Limits::default()
+        };
+        let handler = Echo::default();
+
+        serve(request(1, fastcgi::RESPONDER, false, &[], b"too long"), limits, &handler);
+
+        assert_eq!(handler.error.get(), Some(io::ErrorKind::InvalidData));
+    }
+
+    #[test]
+    fn authorizer()
+    {
+        let handler = AuthorizerHandler::new(Token, Echo::default());
+        let mut input = request(1, fastcgi::AUTHORIZER, true, &[(b"HTTP_AUTHORIZATION", b"secret")], b"");
+        input.extend(request(2, fastcgi::AUTHORIZER, true, &[], b""));
+        input.extend(request(3, fastcgi::FILTER, true, &[], b""));
+        input.extend(request(4, fastcgi::RESPONDER, false, &[], b"body"));
+
+        let records = serve(input, Limits::default(), &handler);
+
+        assert_eq!(ends(&records), vec![
+            (1, fastcgi::REQUEST_COMPLETE),
+            (2, fastcgi::REQUEST_COMPLETE),
+            (3, fastcgi::UNKNOWN_ROLE),
+            (4, fastcgi::REQUEST_COMPLETE),
+        ]);
+
+        let allowed = stdout(&records, 1);
+        assert!(allowed.contains("Status:200\r\n"));
+        assert!(allowed.contains("Variable-USER:admin\r\n"));
+
+        let denied = stdout(&records, 2);
+        assert!(denied.contains("Status:401\r\n"));
+        assert!(denied.ends_with("\r\n\r\ndenied"));
+
+        assert!(stdout(&records, 4).ends_with("body"));
+    }
+}
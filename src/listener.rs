@@ -0,0 +1,808 @@
+//! Socket listeners and accepted transport streams
+use fastcgi;
+
+use std::io;
+use std::io::{Read, Write};
+use std::env;
+use std::net::{IpAddr, Shutdown, TcpListener, TcpStream};
+#[cfg(target_os = "linux")]
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(not(unix))]
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(unix)]
+use std::fs;
+#[cfg(unix)]
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+#[cfg(unix)]
+use std::mem;
+
+#[cfg(unix)]
+use libc;
+
+/// Longest wait for the peer to close a served connection
+const LINGER_TIME: Duration = Duration::from_secs(2);
+
+/// Most input read and dropped before a served connection is closed
+const LINGER_BYTES: usize = 1 << 20;
+
+/// Bound socket
+pub(crate) enum Listener
+{
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener
+{
+    /// Wait for a new transport connection
+    pub(crate) fn accept(&self) -> io::Result<Stream>
+    {
+        let stream = match *self {
+            Listener::Tcp(ref l) => l.accept().map(|(s, _)| Stream::Tcp(s)),
+            #[cfg(unix)]
+            Listener::Unix(ref l) => l.accept().map(|(s, _)| Stream::Unix(s)),
+        }?;
+
+        // some platforms inherit the listener mode
+        stream.set_nonblocking(false)?;
+
+        Ok(stream)
+    }
+
+    /// Take ownership of an already listening socket descriptor
+    #[cfg(unix)]
+    pub(crate) fn from_fd(fd: RawFd) -> io::Result<Listener>
+    {
+        if sock_opt(fd, libc::SO_ACCEPTCONN)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("fd {} is not listening", fd)));
+        }
+
+        if sock_opt(fd, libc::SO_TYPE)? != libc::SOCK_STREAM {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("fd {} is not a stream socket", fd)));
+        }
+
+        let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        if unsafe { libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        match addr.ss_family as libc::c_int {
+            libc::AF_INET | libc::AF_INET6 => Ok(Listener::Tcp(unsafe { TcpListener::from_raw_fd(fd) })),
+            libc::AF_UNIX => Ok(Listener::Unix(unsafe { UnixListener::from_raw_fd(fd) })),
+            family => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("fd {} has unsupported family {}", fd, family))),
+        }
+    }
+
+    /// Still accepting, not shut down
+    #[cfg(unix)]
+    pub(crate) fn is_listening(&self) -> bool
+    {
+        sock_opt(self.as_raw_fd(), libc::SO_ACCEPTCONN).map(|v| v != 0).unwrap_or(false)
+    }
+
+    /// Share the same socket
+    pub(crate) fn try_clone(&self) -> io::Result<Listener>
+    {
+        match *self {
+            Listener::Tcp(ref l) => l.try_clone().map(Listener::Tcp),
+            #[cfg(unix)]
+            Listener::Unix(ref l) => l.try_clone().map(Listener::Unix),
+        }
+    }
+
+    pub(crate) fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>
+    {
+        match *self {
+            Listener::Tcp(ref l) => l.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Listener::Unix(ref l) => l.set_nonblocking(nonblocking),
+        }
+    }
+
+    /// Human readable local address
+    pub(crate) fn address(&self) -> String
+    {
+        match *self {
+            Listener::Tcp(ref l) => l.local_addr()
+                .map(|a| a.to_string())
+                .unwrap_or_default(),
+            #[cfg(unix)]
+            Listener::Unix(ref l) => l.local_addr()
+                .map(|a| unix_address(&a))
+                .unwrap_or_default(),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for Listener
+{
+    fn as_raw_fd(&self) -> RawFd
+    {
+        match *self {
+            Listener::Tcp(ref l) => l.as_raw_fd(),
+            Listener::Unix(ref l) => l.as_raw_fd(),
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn unix_address(addr: &::std::os::unix::net::SocketAddr) -> String
+{
+    #[cfg(target_os = "android")]
+    use std::os::android::net::SocketAddrExt;
+    #[cfg(target_os = "linux")]
+    use std::os::linux::net::SocketAddrExt;
+
+    match addr.as_abstract_name() {
+        Some(name) => format!("unix:@{}", String::from_utf8_lossy(name)),
+        None => format!("unix:{}", addr.as_pathname().map(|p| p.display().to_string()).unwrap_or_default()),
+    }
+}
+
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+fn unix_address(addr: &::std::os::unix::net::SocketAddr) -> String
+{
+    format!("unix:{}", addr.as_pathname().map(|p| p.display().to_string()).unwrap_or_default())
+}
+
+/// Resize pending connections queue of a listening socket
+#[cfg(unix)]
+pub(crate) fn set_backlog(listener: &Listener, backlog: i32) -> io::Result<()>
+{
+    match unsafe { libc::listen(listener.as_raw_fd(), backlog) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Backlog is fixed by the standard library
+#[cfg(not(unix))]
+pub(crate) fn set_backlog(_listener: &Listener, _backlog: i32) -> io::Result<()>
+{
+    Ok(())
+}
+
+/// Connections waiting in the accept queue, known for TCP on Linux only
+#[cfg(target_os = "linux")]
+pub(crate) fn queue_len(listener: &Listener) -> Option<usize>
+{
+    if let Listener::Unix(_) = *listener {
+        return None;
+    }
+
+    let mut info: libc::tcp_info = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+
+    // for a listening socket `tcpi_unacked` is the accept queue length
+    match unsafe { libc::getsockopt(listener.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_INFO, &mut info as *mut _ as *mut libc::c_void, &mut len) } {
+        -1 => None,
+        _ => Some(info.tcpi_unacked as usize),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn queue_len(_listener: &Listener) -> Option<usize>
+{
+    None
+}
+
+/// `count` TCP sockets on the first address that can be bound, the kernel
+/// spreads connections among them with `SO_REUSEPORT`
+#[cfg(target_os = "linux")]
+pub(crate) fn bind_reuseport(addrs: &[SocketAddr], count: usize) -> io::Result<Vec<Listener>>
+{
+    let mut last = None;
+
+    for addr in addrs {
+        let first = match bind_shard(addr) {
+            Ok(first) => first,
+            Err(e) => {
+                last = Some(e);
+                continue;
+            }
+        };
+        // port 0 is resolved by the first socket
+        let addr = first.local_addr()?;
+
+        let mut listeners = vec![Listener::Tcp(first)];
+        for _ in 1..count {
+            listeners.push(Listener::Tcp(bind_shard(&addr)?));
+        }
+
+        return Ok(listeners);
+    }
+
+    Err(last.unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to bind")))
+}
+
+#[cfg(target_os = "linux")]
+fn bind_shard(addr: &SocketAddr) -> io::Result<TcpListener>
+{
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match *addr {
+        SocketAddr::V4(ref a) => {
+            let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = a.port().to_be();
+            sin.sin_addr.s_addr = u32::from(*a.ip()).to_be();
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(ref a) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = a.port().to_be();
+            sin6.sin6_addr.s6_addr = a.ip().octets();
+            sin6.sin6_flowinfo = a.flowinfo();
+            sin6.sin6_scope_id = a.scope_id();
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+
+    let fd = unsafe { libc::socket(storage.ss_family as libc::c_int, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // closed on error
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+
+    // every socket of the group needs the option before bind
+    for &name in &[libc::SO_REUSEADDR, libc::SO_REUSEPORT] {
+        let on: libc::c_int = 1;
+        if unsafe { libc::setsockopt(fd, libc::SOL_SOCKET, name, &on as *const _ as *const libc::c_void, mem::size_of::<libc::c_int>() as libc::socklen_t) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    if unsafe { libc::bind(fd, &storage as *const _ as *const libc::sockaddr, len as libc::socklen_t) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // the default of the standard library
+    if unsafe { libc::listen(fd, 128) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(listener)
+}
+
+/// Parse `FCGI_WEB_SERVER_ADDRS`, malformed entries are skipped
+pub(crate) fn web_server_addrs() -> Option<Vec<IpAddr>>
+{
+    env::var(fastcgi::WEB_SERVER_ADDRS).ok().map(|value| parse_addrs(&value))
+}
+
+/// Comma-separated addresses, IPv4-mapped ones as IPv4
+fn parse_addrs(value: &str) -> Vec<IpAddr>
+{
+    value.split(',')
+        .map(|a| a.trim())
+        .filter(|a| !a.is_empty())
+        .filter_map(|a| match a.parse::<IpAddr>() {
+            Ok(ip) => Some(ip.to_canonical()),
+            Err(_) => {
+                warn!("Skip malformed address {:?} in {}", a, fastcgi::WEB_SERVER_ADDRS);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Listener identifier within a `Client`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ListenerId(pub(crate) usize);
+
+/// Snapshot of listener counters
+#[derive(Debug, Clone)]
+pub struct ListenerStats
+{
+    pub id: ListenerId,
+    /// Local address, `unix:` prefixed for Unix domain sockets
+    pub address: String,
+    /// Connections accepted since start
+    pub accepted: usize,
+    /// Connections in progress
+    pub active: usize,
+    /// Requests handled since start
+    pub requests: usize,
+    /// Connections closed unserved: refused by `FCGI_WEB_SERVER_ADDRS` check
+    /// or over `max_conns` with `Overflow::Close`
+    pub rejected: usize,
+    /// Failed `accept` calls
+    pub errors: usize,
+    /// Connections waiting to be accepted, `None` where the system does not tell
+    pub queue: Option<usize>,
+}
+
+/// Listener registered in a `Client`
+pub(crate) struct Entry
+{
+    pub id: ListenerId,
+    pub listener: Listener,
+    /// More sockets on the same address, one per thread with `SO_REUSEPORT`
+    pub shards: Vec<Listener>,
+    pub accepted: AtomicUsize,
+    pub active: AtomicUsize,
+    pub requests: AtomicUsize,
+    pub rejected: AtomicUsize,
+    pub errors: AtomicUsize,
+}
+
+impl Entry
+{
+    pub(crate) fn with_shards(id: ListenerId, listener: Listener, shards: Vec<Listener>) -> io::Result<Entry>
+    {
+        // a connection may be taken by another thread between poll and accept
+        for listener in Some(&listener).into_iter().chain(&shards) {
+            listener.set_nonblocking(true)?;
+        }
+
+        Ok(Entry {
+            id,
+            listener,
+            shards,
+            accepted: AtomicUsize::new(0),
+            active: AtomicUsize::new(0),
+            requests: AtomicUsize::new(0),
+            rejected: AtomicUsize::new(0),
+            errors: AtomicUsize::new(0),
+        })
+    }
+
+    /// Same socket and totals under another id
+    pub(crate) fn try_clone_as(&self, id: ListenerId) -> io::Result<Entry>
+    {
+        let shards = self.shards.iter()
+            .map(|s| s.try_clone())
+            .collect::<io::Result<Vec<_>>>()?;
+        let entry = Entry::with_shards(id, self.listener.try_clone()?, shards)?;
+        entry.accepted.store(self.accepted.load(Ordering::Relaxed), Ordering::Relaxed);
+        entry.requests.store(self.requests.load(Ordering::Relaxed), Ordering::Relaxed);
+        entry.rejected.store(self.rejected.load(Ordering::Relaxed), Ordering::Relaxed);
+        entry.errors.store(self.errors.load(Ordering::Relaxed), Ordering::Relaxed);
+
+        Ok(entry)
+    }
+
+    pub(crate) fn stats(&self) -> ListenerStats
+    {
+        ListenerStats {
+            id: self.id,
+            address: self.listener.address(),
+            accepted: self.accepted.load(Ordering::Relaxed),
+            active: self.active.load(Ordering::Relaxed),
+            requests: self.requests.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            queue: Some(&self.listener).into_iter()
+                .chain(&self.shards)
+                .map(queue_len)
+                .sum(),
+        }
+    }
+
+    /// Socket accepted on by thread `n`, threads share sockets when there are fewer
+    pub(crate) fn shard(&self, n: usize) -> &Listener
+    {
+        match n % (self.shards.len() + 1) {
+            0 => &self.listener,
+            i => &self.shards[i - 1],
+        }
+    }
+
+    /// Leave the `SO_REUSEPORT` group, connections go to the first socket only
+    ///
+    /// Descriptors stay open, so inherited copies are safe to drop later.
+    #[cfg(unix)]
+    pub(crate) fn close_shards(&self)
+    {
+        for shard in &self.shards {
+            unsafe { libc::shutdown(shard.as_raw_fd(), libc::SHUT_RDWR) };
+        }
+    }
+
+    /// Count the connection as active until the guard is dropped
+    pub(crate) fn activate(&self) -> Active<'_>
+    {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+        self.active.fetch_add(1, Ordering::Relaxed);
+
+        Active(&self.active)
+    }
+}
+
+/// Active connection guard, released on handler panic as well
+pub(crate) struct Active<'e>(&'e AtomicUsize);
+
+impl<'e> Drop for Active<'e>
+{
+    fn drop(&mut self)
+    {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Interrupt a thread blocked in `poll`
+#[cfg(unix)]
+pub(crate) struct Waker
+{
+    rx: UnixStream,
+    tx: UnixStream,
+}
+
+#[cfg(unix)]
+impl Waker
+{
+    pub(crate) fn new() -> io::Result<Waker>
+    {
+        let (rx, tx) = UnixStream::pair()?;
+        rx.set_nonblocking(true)?;
+        tx.set_nonblocking(true)?;
+
+        Ok(Waker { rx, tx })
+    }
+
+    pub(crate) fn wake(&self)
+    {
+        // a full buffer already wakes the reader
+        let _ = (&self.tx).write(&[1]);
+    }
+
+    fn drain(&self)
+    {
+        let mut buf = [0; 64];
+        while let Ok(n) = (&self.rx).read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+        }
+    }
+}
+
+/// Wait for pending connections or a wake up
+#[cfg(unix)]
+pub(crate) fn poll(entries: &[Arc<Entry>], waker: &Waker, shard: usize) -> io::Result<Vec<Arc<Entry>>>
+{
+    let mut fds: Vec<libc::pollfd> = entries.iter()
+        .map(|e| e.shard(shard).as_raw_fd())
+        .chain(Some(waker.rx.as_raw_fd()))
+        .map(|fd| libc::pollfd { fd, events: libc::POLLIN, revents: 0 })
+        .collect();
+
+    if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } == -1 {
+        let e = io::Error::last_os_error();
+        return match e.kind() {
+            io::ErrorKind::Interrupted => Ok(Vec::new()),
+            _ => Err(e),
+        };
+    }
+
+    if fds.last().is_some_and(|fd| fd.revents != 0) {
+        waker.drain();
+    }
+
+    Ok(entries.iter()
+        .zip(fds)
+        .filter(|&(_, fd)| fd.revents != 0)
+        .map(|(e, _)| e.clone())
+        .collect())
+}
+
+/// Interrupt a thread blocked in `poll`
+#[cfg(not(unix))]
+pub(crate) struct Waker;
+
+#[cfg(not(unix))]
+impl Waker
+{
+    pub(crate) fn new() -> io::Result<Waker>
+    {
+        Ok(Waker)
+    }
+
+    pub(crate) fn wake(&self)
+    {
+    }
+}
+
+/// Try every listener, no readiness API without `libc`
+#[cfg(not(unix))]
+pub(crate) fn poll(entries: &[Arc<Entry>], _waker: &Waker, _shard: usize) -> io::Result<Vec<Arc<Entry>>>
+{
+    thread::sleep(Duration::from_millis(10));
+
+    Ok(entries.to_vec())
+}
+
+/// Integer socket option
+#[cfg(unix)]
+fn sock_opt(fd: RawFd, name: libc::c_int) -> io::Result<libc::c_int>
+{
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+
+    match unsafe { libc::getsockopt(fd, libc::SOL_SOCKET, name, &mut value as *mut _ as *mut libc::c_void, &mut len) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(value),
+    }
+}
+
+/// Accepted transport connection
+#[derive(Debug)]
+pub(crate) enum Stream
+{
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream
+{
+    pub(crate) fn try_clone(&self) -> io::Result<Stream>
+    {
+        match *self {
+            Stream::Tcp(ref s) => s.try_clone().map(Stream::Tcp),
+            #[cfg(unix)]
+            Stream::Unix(ref s) => s.try_clone().map(Stream::Unix),
+        }
+    }
+
+    pub(crate) fn shutdown(&self, how: Shutdown) -> io::Result<()>
+    {
+        match *self {
+            Stream::Tcp(ref s) => s.shutdown(how),
+            #[cfg(unix)]
+            Stream::Unix(ref s) => s.shutdown(how),
+        }
+    }
+
+    /// Stop sending and drop what the peer still sends until it closes
+    ///
+    /// Closing with unread input resets the connection, the peer may lose the response then.
+    pub(crate) fn linger(&mut self)
+    {
+        if let Err(e) = self.shutdown(Shutdown::Write) {
+            debug!("Half-close connection: {}", e);
+            return;
+        }
+
+        let deadline = Instant::now() + LINGER_TIME;
+        let mut buf = [0; 8192];
+        let mut dropped = 0;
+        while dropped < LINGER_BYTES {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left == Duration::from_secs(0) || self.set_read_timeout(Some(left)).is_err() {
+                break;
+            }
+
+            match self.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => dropped += n,
+            }
+        }
+    }
+
+    pub(crate) fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>
+    {
+        match *self {
+            Stream::Tcp(ref s) => s.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(ref s) => s.set_read_timeout(timeout),
+        }
+    }
+
+    pub(crate) fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>
+    {
+        match *self {
+            Stream::Tcp(ref s) => s.set_write_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(ref s) => s.set_write_timeout(timeout),
+        }
+    }
+
+    /// `TCP_NODELAY`, no-op for other transports
+    pub(crate) fn set_nodelay(&self, nodelay: bool) -> io::Result<()>
+    {
+        match *self {
+            Stream::Tcp(ref s) => s.set_nodelay(nodelay),
+            #[cfg(unix)]
+            Stream::Unix(_) => Ok(()),
+        }
+    }
+
+    /// Remote IP address, `None` for non-TCP transport
+    pub(crate) fn peer_ip(&self) -> Option<IpAddr>
+    {
+        match *self {
+            Stream::Tcp(ref s) => s.peer_addr().ok().map(|a| a.ip().to_canonical()),
+            #[cfg(unix)]
+            Stream::Unix(_) => None,
+        }
+    }
+
+    pub(crate) fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>
+    {
+        match *self {
+            Stream::Tcp(ref s) => s.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Stream::Unix(ref s) => s.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+impl Read for Stream
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>
+    {
+        match *self {
+            Stream::Tcp(ref mut s) => s.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize>
+    {
+        match *self {
+            Stream::Tcp(ref mut s) => s.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()>
+    {
+        match *self {
+            Stream::Tcp(ref mut s) => s.flush(),
+            #[cfg(unix)]
+            Stream::Unix(ref mut s) => s.flush(),
+        }
+    }
+}
+
+/// Unix domain socket options
+///
+/// A path starting with `@` is bound in the abstract namespace on Linux,
+/// such socket has no file so mode and owner are ignored.
+#[cfg(unix)]
+#[derive(Debug, Clone)]
+pub struct UnixSocket
+{
+    path: PathBuf,
+    mode: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+}
+
+#[cfg(unix)]
+impl UnixSocket
+{
+    /// Constructor
+    pub fn new<P: AsRef<Path>>(path: P) -> UnixSocket
+    {
+        UnixSocket {
+            path: path.as_ref().to_path_buf(),
+            mode: None,
+            uid: None,
+            gid: None,
+        }
+    }
+
+    /// Set socket file permissions, e.g. `0o660`
+    pub fn mode(&mut self, mode: u32) -> &mut UnixSocket
+    {
+        self.mode = Some(mode);
+
+        self
+    }
+
+    /// Set socket file owner and group, `None` keeps current one
+    pub fn owner(&mut self, uid: Option<u32>, gid: Option<u32>) -> &mut UnixSocket
+    {
+        self.uid = uid;
+        self.gid = gid;
+
+        self
+    }
+
+    /// Socket path
+    pub fn path(&self) -> &Path
+    {
+        &self.path
+    }
+
+    /// Bind socket, a stale socket file left by a dead process is removed
+    pub(crate) fn bind(&self) -> io::Result<UnixListener>
+    {
+        if let Some(listener) = self.bind_abstract() {
+            return listener;
+        }
+
+        self.remove_stale()?;
+
+        let listener = UnixListener::bind(&self.path)?;
+
+        if let Some(mode) = self.mode {
+            fs::set_permissions(&self.path, fs::Permissions::from_mode(mode))?;
+        }
+
+        if self.uid.is_some() || self.gid.is_some() {
+            ::std::os::unix::fs::chown(&self.path, self.uid, self.gid)?;
+        }
+
+        Ok(listener)
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn bind_abstract(&self) -> Option<io::Result<UnixListener>>
+    {
+        #[cfg(target_os = "android")]
+        use std::os::android::net::SocketAddrExt;
+        #[cfg(target_os = "linux")]
+        use std::os::linux::net::SocketAddrExt;
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::net::SocketAddr;
+
+        let name = self.path.as_os_str().as_bytes();
+        if name.first() != Some(&b'@') {
+            return None;
+        }
+
+        Some(SocketAddr::from_abstract_name(&name[1..]).and_then(|addr| UnixListener::bind_addr(&addr)))
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn bind_abstract(&self) -> Option<io::Result<UnixListener>>
+    {
+        None
+    }
+
+    /// Remove socket file if nobody listens on it
+    fn remove_stale(&self) -> io::Result<()>
+    {
+        match fs::symlink_metadata(&self.path) {
+            Ok(ref meta) if meta.file_type().is_socket() => (),
+            _ => return Ok(()),
+        }
+
+        match UnixStream::connect(&self.path) {
+            Ok(_) => Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is in use", self.path.display()),
+            )),
+            Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(&self.path),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::net::IpAddr;
+
+    use super::parse_addrs;
+
+    #[test]
+    fn web_server_addrs()
+    {
+        let addrs: Vec<IpAddr> = ["127.0.0.1", "::1", "10.0.0.1"].iter().map(|a| a.parse().unwrap()).collect();
+
+        assert_eq!(parse_addrs("127.0.0.1, ::1,bad,,::ffff:10.0.0.1"), addrs);
+        assert!(parse_addrs("").is_empty());
+    }
+}
@@ -0,0 +1,337 @@
+//! Process manager modes and the scoreboard shared with worker processes
+use std::io;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use libc;
+
+use http::{Request, Response, Role};
+use shutdown::ShutdownHandle;
+use status::{RunningRequest, Status};
+use Handler;
+
+/// How `run_prefork` sizes the pool of worker processes, see php-fpm `pm`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessManager
+{
+    /// Fixed number of workers
+    Static
+    {
+        children: usize,
+    },
+    /// Idle workers are kept between `min_spare` and `max_spare`
+    Dynamic
+    {
+        max_children: usize,
+        /// Workers forked on start
+        start_servers: usize,
+        min_spare: usize,
+        max_spare: usize,
+    },
+    /// Workers are forked when connections wait and no one is idle,
+    /// stopped after `idle_timeout` without requests
+    OnDemand
+    {
+        max_children: usize,
+        idle_timeout: Duration,
+    },
+}
+
+impl Default for ProcessManager
+{
+    fn default() -> ProcessManager
+    {
+        ProcessManager::Static {
+            children: 1,
+        }
+    }
+}
+
+impl ProcessManager
+{
+    /// Upper bound of workers
+    pub(crate) fn max_children(&self) -> usize
+    {
+        match *self {
+            ProcessManager::Static { children } => children,
+            ProcessManager::Dynamic { max_children, .. } => max_children,
+            ProcessManager::OnDemand { max_children, .. } => max_children,
+        }.max(1)
+    }
+
+    /// Spare limits must fit each other and the number of workers, as php-fpm checks them
+    pub(crate) fn validate(&self) -> io::Result<()>
+    {
+        if let ProcessManager::Dynamic { max_children, start_servers, min_spare, max_spare } = *self {
+            let error = if max_children == 0 {
+                Some("max_children must be positive".to_owned())
+            } else if min_spare > max_spare {
+                Some(format!("min_spare {} exceeds max_spare {}", min_spare, max_spare))
+            } else if max_spare > max_children {
+                Some(format!("max_spare {} exceeds max_children {}", max_spare, max_children))
+            } else if start_servers < min_spare || start_servers > max_spare {
+                Some(format!("start_servers {} is not between min_spare {} and max_spare {}", start_servers, min_spare, max_spare))
+            } else {
+                None
+            };
+
+            if let Some(error) = error {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("dynamic process manager: {}", error)));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Workers forked on start
+    pub(crate) fn start_children(&self) -> usize
+    {
+        match *self {
+            ProcessManager::Static { .. } => self.max_children(),
+            ProcessManager::Dynamic { start_servers, .. } => start_servers.min(self.max_children()),
+            ProcessManager::OnDemand { .. } => 0,
+        }
+    }
+}
+
+/// Slot is not taken by a worker
+pub(crate) const FREE: usize = 0;
+/// Worker waits for a request
+pub(crate) const IDLE: usize = 1;
+/// Worker runs `Handler::process`
+pub(crate) const BUSY: usize = 2;
+/// Worker served `max_requests` and finishes, a replacement may be forked
+pub(crate) const EXITING: usize = 3;
+
+/// Drain time of stopped workers and workers whose master is gone
+pub(crate) const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Stored part of `REQUEST_URI`
+const URI_LEN: usize = 256;
+
+/// Counters of every worker ever forked
+#[repr(C)]
+pub(crate) struct Totals
+{
+    pub accepted: AtomicU64,
+    pub requests: AtomicU64,
+}
+
+/// Worker state visible to the master
+#[repr(C)]
+pub(crate) struct Slot
+{
+    pub state: AtomicUsize,
+    /// Milliseconds since the scoreboard epoch of the last state change
+    pub since: AtomicU64,
+    pub requests: AtomicU64,
+    pub pid: AtomicU32,
+    uri_len: AtomicUsize,
+    uri: [AtomicU8; URI_LEN],
+}
+
+/// Worker slots in memory shared between the master and forked workers
+pub(crate) struct Scoreboard
+{
+    totals: *mut Totals,
+    slots: *mut Slot,
+    len: usize,
+    epoch: Instant,
+}
+
+// the mapping holds atomics only
+unsafe impl Send for Scoreboard {}
+unsafe impl Sync for Scoreboard {}
+
+impl Scoreboard
+{
+    pub(crate) fn new(len: usize) -> io::Result<Scoreboard>
+    {
+        let map = unsafe {
+            libc::mmap(ptr::null_mut(), Self::size(len), libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED | libc::MAP_ANONYMOUS, -1, 0)
+        };
+
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        // anonymous mapping is zeroed, every slot is `FREE`
+        Ok(Scoreboard {
+            totals: map as *mut Totals,
+            slots: unsafe { (map as *mut Totals).add(1) as *mut Slot },
+            len,
+            epoch: Instant::now(),
+        })
+    }
+
+    /// Mapping size, `Totals` alignment fits `Slot`
+    fn size(len: usize) -> usize
+    {
+        mem::size_of::<Totals>() + len * mem::size_of::<Slot>()
+    }
+
+    pub(crate) fn totals(&self) -> &Totals
+    {
+        unsafe { &*self.totals }
+    }
+
+    pub(crate) fn len(&self) -> usize
+    {
+        self.len
+    }
+
+    pub(crate) fn slot(&self, i: usize) -> &Slot
+    {
+        assert!(i < self.len);
+
+        unsafe { &*self.slots.add(i) }
+    }
+
+    pub(crate) fn set(&self, i: usize, state: usize)
+    {
+        let slot = self.slot(i);
+        slot.since.store(self.now(), Ordering::SeqCst);
+        slot.state.store(state, Ordering::SeqCst);
+    }
+
+    /// Remember the request the worker runs, long values are truncated
+    pub(crate) fn set_uri(&self, i: usize, uri: &[u8])
+    {
+        let slot = self.slot(i);
+        let uri = &uri[..uri.len().min(URI_LEN)];
+
+        for (cell, &b) in slot.uri.iter().zip(uri) {
+            cell.store(b, Ordering::Relaxed);
+        }
+        slot.uri_len.store(uri.len(), Ordering::SeqCst);
+    }
+
+    /// Request of the worker, may be torn while the worker starts another one
+    pub(crate) fn uri(&self, i: usize) -> String
+    {
+        let slot = self.slot(i);
+        let len = slot.uri_len.load(Ordering::SeqCst);
+        let uri: Vec<u8> = slot.uri[..len].iter().map(|b| b.load(Ordering::Relaxed)).collect();
+
+        String::from_utf8_lossy(&uri).into_owned()
+    }
+
+    /// Every worker process, `listen_queue` is taken from the listeners
+    pub(crate) fn status(&self, listen_queue: Option<usize>) -> Status
+    {
+        let mut status = Status {
+            workers: 0,
+            active: 0,
+            idle: 0,
+            accepted: self.totals().accepted.load(Ordering::Relaxed),
+            requests: self.totals().requests.load(Ordering::Relaxed),
+            listen_queue,
+            running: Vec::new(),
+        };
+
+        for i in 0..self.len {
+            match self.slot(i).state.load(Ordering::SeqCst) {
+                FREE => continue,
+                BUSY => {
+                    status.active += 1;
+                    status.running.push(RunningRequest {
+                        worker: format!("pid {}", self.slot(i).pid.load(Ordering::SeqCst)),
+                        request_uri: self.uri(i),
+                        elapsed: self.elapsed(i),
+                    });
+                }
+                _ => status.idle += 1,
+            }
+            status.workers += 1;
+        }
+
+        status
+    }
+
+    /// Time the slot spent in its current state
+    pub(crate) fn elapsed(&self, i: usize) -> Duration
+    {
+        Duration::from_millis(self.now().saturating_sub(self.slot(i).since.load(Ordering::SeqCst)))
+    }
+
+    /// Same clock in every process, `Instant` is monotonic system-wide
+    fn now(&self) -> u64
+    {
+        let elapsed = self.epoch.elapsed();
+
+        elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis())
+    }
+}
+
+impl Drop for Scoreboard
+{
+    fn drop(&mut self)
+    {
+        unsafe { libc::munmap(self.totals as *mut libc::c_void, Self::size(self.len)) };
+    }
+}
+
+/// Handler reporting busy state to the scoreboard
+pub(crate) struct Tracked<'a, T: 'a>
+{
+    pub board: &'a Scoreboard,
+    pub slot: usize,
+    pub handler: &'a T,
+    /// Stop the worker after this many requests
+    pub max_requests: Option<u64>,
+    pub shutdown: ShutdownHandle,
+}
+
+impl<'a, T: Handler> Handler for Tracked<'a, T>
+{
+    fn process(&self, request: &mut Request, response: &mut Response)
+    {
+        self.board.set_uri(self.slot, request.header(b"REQUEST_URI").map_or(&[][..], |v| &v[..]));
+        self.board.set(self.slot, BUSY);
+        self.handler.process(request, response);
+        self.board.totals().requests.fetch_add(1, Ordering::Relaxed);
+        let requests = self.board.slot(self.slot).requests.fetch_add(1, Ordering::SeqCst) + 1;
+
+        match self.max_requests {
+            Some(max) if requests >= max => {
+                // the response is sent before the worker stops
+                info!("Worker served {} requests, recycle", requests);
+                self.board.set(self.slot, EXITING);
+                self.shutdown.shutdown(DRAIN_TIMEOUT);
+            }
+            _ => self.board.set(self.slot, IDLE),
+        }
+    }
+
+    fn supports(&self, role: Role) -> bool
+    {
+        self.handler.supports(role)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::io;
+
+    use super::ProcessManager;
+
+    fn dynamic(max_children: usize, start_servers: usize, min_spare: usize, max_spare: usize) -> io::Result<()>
+    {
+        ProcessManager::Dynamic { max_children, start_servers, min_spare, max_spare }.validate()
+    }
+
+    #[test]
+    fn dynamic_limits()
+    {
+        assert!(dynamic(10, 2, 1, 3).is_ok());
+        assert!(dynamic(10, 3, 3, 3).is_ok());
+
+        for &(max_children, start_servers, min_spare, max_spare) in &[(0, 0, 0, 0), (10, 2, 4, 3), (2, 2, 1, 3), (10, 5, 1, 3), (10, 0, 1, 3)] {
+            let e = dynamic(max_children, start_servers, min_spare, max_spare).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+}
@@ -0,0 +1,84 @@
+//! Worker threads serving accepted connections
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread::{self, JoinHandle};
+
+use listener::{Entry, Stream};
+
+/// Accepted connection and its listener
+pub(crate) type Job = (Arc<Entry>, Stream);
+
+/// Fixed set of threads fed through a bounded queue
+pub(crate) struct Pool
+{
+    sender: SyncSender<Job>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl Pool
+{
+    /// Start `size` workers, up to `queue` connections wait for a free one
+    pub(crate) fn new<F>(size: usize, queue: usize, serve: F) -> io::Result<Pool>
+        where F: Fn(&Entry, Stream) + Send + Sync + 'static
+    {
+        let (sender, receiver) = mpsc::sync_channel(queue);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let serve = Arc::new(serve);
+
+        let mut workers = Vec::with_capacity(size);
+        for n in 0..size {
+            let receiver = receiver.clone();
+            let serve = serve.clone();
+
+            workers.push(thread::Builder::new()
+                .name(format!("gfcgi-worker-{}", n))
+                .spawn(move || work(&receiver, &*serve))?);
+        }
+
+        Ok(Pool {
+            sender,
+            workers,
+        })
+    }
+
+    /// Hand the connection over, blocks while the queue is full
+    pub(crate) fn execute(&self, job: Job)
+    {
+        if self.sender.send(job).is_err() {
+            error!("No workers left, connection dropped");
+        }
+    }
+
+    /// Let workers serve queued connections and wait for them
+    pub(crate) fn join(self)
+    {
+        drop(self.sender);
+
+        for worker in self.workers {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn work<F: Fn(&Entry, Stream)>(receiver: &Mutex<Receiver<Job>>, serve: &F)
+{
+    loop {
+        let job = receiver.lock().unwrap().recv();
+        let (entry, stream) = match job {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+
+        guard(|| serve(&entry, stream));
+    }
+}
+
+/// Serve one connection, the worker keeps serving after a handler panic
+pub(crate) fn guard<F: FnOnce()>(serve: F)
+{
+    if panic::catch_unwind(AssertUnwindSafe(serve)).is_err() {
+        error!("Handler panicked, connection closed");
+    }
+}
@@ -0,0 +1,441 @@
+//! Prefork process model, every worker process accepts on the shared listeners
+use std::io;
+use std::io::{Read, Write};
+use std::net::Shutdown;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libc;
+
+use accept::Backoff;
+use pm::{ProcessManager, Scoreboard, DRAIN_TIMEOUT, EXITING, FREE, IDLE};
+use signals;
+use upgrade;
+use Client;
+
+/// Interval of checking for exited workers, scaling and shutdown
+const TICK: Duration = Duration::from_millis(100);
+
+/// A worker exited sooner is restarted with growing delay
+const MIN_LIFETIME: Duration = Duration::from_secs(1);
+
+/// Worker process seen from the master
+struct Worker
+{
+    pid: libc::pid_t,
+    /// Shutdown is sent here, the worker drains on EOF as well
+    control: UnixStream,
+    slot: usize,
+    started: Instant,
+}
+
+/// Supervisor of worker processes
+struct Master<'c, F>
+{
+    client: &'c Client,
+    pm: ProcessManager,
+    board: Arc<Scoreboard>,
+    serve: F,
+    workers: Vec<Worker>,
+    /// Workers asked to stop, not counted by the manager
+    retiring: Vec<Worker>,
+    backoff: Backoff,
+}
+
+/// Fork workers running `serve` and keep them as `pm` says until shutdown
+pub(crate) fn run<F>(client: &Client, pm: ProcessManager, serve: F) -> io::Result<()>
+    where F: Fn(&Client, &Scoreboard, usize)
+{
+    // workers must not report readiness, the master does once they are forked
+    let ready = client.inner.ready.lock().unwrap().take();
+
+    // a process may not run for every `SO_REUSEPORT` socket, connections there would wait forever
+    for entry in client.inner.listeners.lock().unwrap().iter() {
+        entry.close_shards();
+    }
+
+    // workers report to the scoreboard, `Client::status` reads it,
+    // retiring workers keep their slots while replacements start
+    let board = Arc::new(Scoreboard::new(pm.max_children() * 2)?);
+    *client.inner.scoreboard.lock().unwrap() = Some(board.clone());
+
+    let mut master = Master {
+        client,
+        pm,
+        board,
+        serve,
+        workers: Vec::new(),
+        retiring: Vec::new(),
+        backoff: Backoff::new(),
+    };
+
+    for _ in 0..pm.start_children() {
+        if let Err(e) = master.fork() {
+            master.stop(Duration::from_secs(0));
+            return Err(e);
+        }
+    }
+    info!("Started {} worker processes, {:?}", master.workers.len(), pm);
+
+    if let Some(ready) = ready {
+        upgrade::notify(ready);
+    }
+
+    // a thread could hold locks while forking, signals are read here between forks
+    let mut signals = signals::take();
+    if let Some(ref rx) = signals {
+        rx.set_nonblocking(true)?;
+    }
+
+    while !client.inner.shutdown.load(Ordering::SeqCst) {
+        if let Some(ref mut rx) = signals {
+            signals::dispatch_pending(&client.inner, rx);
+        }
+        if client.inner.reload.swap(false, Ordering::SeqCst) {
+            master.replace();
+        }
+        if client.inner.reopen.swap(false, Ordering::SeqCst) {
+            master.kill(libc::SIGUSR1);
+        }
+
+        master.reap();
+        master.scale();
+    }
+
+    master.stop(*client.inner.drain_timeout.lock().unwrap());
+    client.inner.scoreboard.lock().unwrap().take();
+
+    Ok(())
+}
+
+impl<'c, F: Fn(&Client, &Scoreboard, usize)> Master<'c, F>
+{
+    /// Forget exited workers, crashed ones delay further forks
+    fn reap(&mut self)
+    {
+        // recycled workers are replaced while they finish
+        let mut i = 0;
+        while i < self.workers.len() {
+            if self.board.slot(self.workers[i].slot).state.load(Ordering::SeqCst) == EXITING {
+                let worker = self.workers.swap_remove(i);
+                self.retiring.push(worker);
+            } else {
+                i += 1;
+            }
+        }
+
+        let mut i = 0;
+        while i < self.retiring.len() {
+            match exited(self.retiring[i].pid) {
+                None => i += 1,
+                Some(_) => self.release(i, true),
+            }
+        }
+
+        let mut i = 0;
+        while i < self.workers.len() {
+            match exited(self.workers[i].pid) {
+                None => i += 1,
+                Some(status) => {
+                    match status {
+                        Some(status) => warn!("Worker process {} {}", self.workers[i].pid, describe(status)),
+                        None => warn!("Worker process {} exited", self.workers[i].pid),
+                    }
+
+                    if self.workers[i].started.elapsed() < MIN_LIFETIME {
+                        self.backoff.wait();
+                    } else {
+                        self.backoff.reset();
+                    }
+                    self.release(i, false);
+                }
+            }
+        }
+    }
+
+    fn release(&mut self, i: usize, retiring: bool)
+    {
+        let worker = if retiring {
+            self.retiring.swap_remove(i)
+        } else {
+            self.workers.swap_remove(i)
+        };
+
+        self.board.set(worker.slot, FREE);
+    }
+
+    /// Fork or stop workers once, waits up to `TICK`
+    fn scale(&mut self)
+    {
+        let alive = self.workers.len();
+        let idle: Vec<usize> = (0..alive)
+            .filter(|&i| self.board.slot(self.workers[i].slot).state.load(Ordering::SeqCst) == IDLE)
+            .collect();
+
+        match self.pm {
+            ProcessManager::Static { .. } => {
+                self.spawn(self.pm.max_children().saturating_sub(alive));
+            }
+            ProcessManager::Dynamic { min_spare, max_spare, .. } => {
+                if idle.len() < min_spare {
+                    self.spawn(min_spare - idle.len());
+                } else if idle.len() > max_spare {
+                    // one per tick as php-fpm does, a burst may come back
+                    self.retire(self.longest_idle(&idle));
+                }
+            }
+            ProcessManager::OnDemand { idle_timeout, .. } => {
+                for &i in idle.iter().rev() {
+                    if self.board.elapsed(self.workers[i].slot) >= idle_timeout {
+                        self.retire(i);
+                    }
+                }
+
+                if idle.is_empty() && alive < self.pm.max_children() {
+                    if pending(self.client, TICK) {
+                        self.spawn(1);
+                    }
+                    return;
+                }
+            }
+        }
+
+        thread::sleep(TICK);
+    }
+
+    /// Fork up to `count` workers within `max_children`
+    fn spawn(&mut self, count: usize)
+    {
+        let count = count.min(self.pm.max_children().saturating_sub(self.workers.len()));
+
+        for _ in 0..count {
+            if let Err(e) = self.fork() {
+                error!("Fork worker process: {}", e);
+                self.backoff.wait();
+                break;
+            }
+        }
+    }
+
+    fn longest_idle(&self, idle: &[usize]) -> usize
+    {
+        *idle.iter()
+            .max_by_key(|&&i| self.board.elapsed(self.workers[i].slot))
+            .unwrap()
+    }
+
+    /// Ask the worker to finish and stop counting it
+    fn retire(&mut self, i: usize)
+    {
+        let worker = self.workers.swap_remove(i);
+        info!("Stop idle worker process {}", worker.pid);
+
+        send_stop(&worker, DRAIN_TIMEOUT);
+        self.retiring.push(worker);
+    }
+
+    /// Retire every worker and start fresh ones right away
+    fn replace(&mut self)
+    {
+        info!("Replace {} worker processes", self.workers.len());
+
+        for worker in self.workers.drain(..) {
+            send_stop(&worker, DRAIN_TIMEOUT);
+            self.retiring.push(worker);
+        }
+
+        let count = self.pm.start_children();
+        self.spawn(count);
+    }
+
+    /// Pass a signal to every worker
+    fn kill(&self, signal: libc::c_int)
+    {
+        for worker in self.workers.iter().chain(&self.retiring) {
+            if unsafe { libc::kill(worker.pid, signal) } == -1 {
+                warn!("Signal worker process {}: {}", worker.pid, io::Error::last_os_error());
+            }
+        }
+    }
+
+    fn fork(&mut self) -> io::Result<()>
+    {
+        let slot = (0..self.board.len())
+            .find(|&slot| !self.workers.iter().chain(&self.retiring).any(|w| w.slot == slot))
+            .ok_or_else(|| io::Error::other("no free scoreboard slot"))?;
+
+        self.board.slot(slot).requests.store(0, Ordering::SeqCst);
+        self.board.set_uri(slot, b"");
+        self.board.set(slot, IDLE);
+
+        let worker = match fork(self.client, &self.workers, &self.retiring, &self.board, slot, &self.serve) {
+            Ok(worker) => worker,
+            Err(e) => {
+                self.board.set(slot, FREE);
+                return Err(e);
+            }
+        };
+        self.board.slot(slot).pid.store(worker.pid as u32, Ordering::SeqCst);
+        self.workers.push(worker);
+
+        Ok(())
+    }
+
+    /// Ask every worker to drain and wait for them
+    fn stop(&mut self, timeout: Duration)
+    {
+        for worker in &self.workers {
+            send_stop(worker, timeout);
+        }
+
+        for worker in self.workers.drain(..).chain(self.retiring.drain(..)) {
+            match wait(worker.pid, 0) {
+                Ok(Some(status)) => info!("Worker process {} {}", worker.pid, describe(status)),
+                Ok(None) => (),
+                Err(ref e) if e.raw_os_error() == Some(libc::ECHILD) => info!("Worker process {} exited", worker.pid),
+                Err(e) => warn!("Wait worker process {}: {}", worker.pid, e),
+            }
+        }
+    }
+}
+
+/// Tell the worker to drain within `timeout`
+fn send_stop(worker: &Worker, timeout: Duration)
+{
+    let millis = timeout.as_secs() * 1000 + u64::from(timeout.subsec_millis());
+
+    // the worker may be already gone
+    let _ = (&worker.control).write_all(millis.to_string().as_bytes());
+    let _ = worker.control.shutdown(Shutdown::Write);
+}
+
+/// A connection waits on some listener
+fn pending(client: &Client, timeout: Duration) -> bool
+{
+    let mut fds: Vec<libc::pollfd> = client.inner.listeners.lock().unwrap()
+        .iter()
+        .map(|e| libc::pollfd { fd: e.listener.as_raw_fd(), events: libc::POLLIN, revents: 0 })
+        .collect();
+
+    if fds.is_empty() {
+        thread::sleep(timeout);
+        return false;
+    }
+
+    let millis = timeout.as_secs() as libc::c_int * 1000 + timeout.subsec_millis() as libc::c_int;
+    let ready = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, millis) };
+    if ready <= 0 {
+        return false;
+    }
+
+    // wait for a worker to accept
+    thread::sleep(Duration::from_millis(1));
+
+    true
+}
+
+fn fork<F>(client: &Client, workers: &[Worker], retiring: &[Worker], board: &Scoreboard, slot: usize, serve: &F) -> io::Result<Worker>
+    where F: Fn(&Client, &Scoreboard, usize)
+{
+    let (control, worker_control) = UnixStream::pair()?;
+
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => {
+            // control sockets of siblings belong to the master
+            for sibling in workers.iter().chain(retiring) {
+                unsafe { libc::close(sibling.control.as_raw_fd()) };
+            }
+            drop(control);
+
+            let code = match panic::catch_unwind(AssertUnwindSafe(|| work(client, worker_control, board, slot, serve))) {
+                Ok(Ok(())) => 0,
+                Ok(Err(e)) => {
+                    error!("Worker process: {}", e);
+                    1
+                }
+                Err(_) => 101,
+            };
+            process::exit(code);
+        }
+        pid => Ok(Worker {
+            pid,
+            control,
+            slot,
+            started: Instant::now(),
+        }),
+    }
+}
+
+/// Worker process body, never returns to the caller of `run_prefork`
+fn work<F>(client: &Client, mut control: UnixStream, board: &Scoreboard, slot: usize, serve: &F) -> io::Result<()>
+    where F: Fn(&Client, &Scoreboard, usize)
+{
+    signals::after_fork(client.inner.clone())?;
+
+    let shutdown = client.shutdown_handle();
+    thread::Builder::new()
+        .name("gfcgi-control".to_owned())
+        .spawn(move || {
+            let mut message = String::new();
+            let timeout = match control.read_to_string(&mut message) {
+                Ok(_) => message.parse().map(Duration::from_millis).unwrap_or(DRAIN_TIMEOUT),
+                Err(_) => DRAIN_TIMEOUT,
+            };
+
+            shutdown.shutdown(timeout);
+        })?;
+
+    serve(client, board, slot);
+
+    Ok(())
+}
+
+/// Exit status of the child, `None` if it is still running
+/// Status of a worker gone, `Some(None)` if the system reaped it, `None` while it runs
+fn exited(pid: libc::pid_t) -> Option<Option<libc::c_int>>
+{
+    match wait(pid, libc::WNOHANG) {
+        Ok(status) => status.map(Some),
+        // `SIGCHLD` is ignored, exited children are not kept for `waitpid`
+        Err(ref e) if e.raw_os_error() == Some(libc::ECHILD) => Some(None),
+        // the workers must not be left behind, try again next tick
+        Err(e) => {
+            warn!("Wait worker process {}: {}", pid, e);
+            None
+        }
+    }
+}
+
+fn wait(pid: libc::pid_t, options: libc::c_int) -> io::Result<Option<libc::c_int>>
+{
+    let mut status = 0;
+
+    loop {
+        match unsafe { libc::waitpid(pid, &mut status, options) } {
+            0 => return Ok(None),
+            -1 => {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e);
+                }
+            }
+            _ => return Ok(Some(status)),
+        }
+    }
+}
+
+fn describe(status: libc::c_int) -> String
+{
+    if libc::WIFSIGNALED(status) {
+        format!("killed by signal {}", libc::WTERMSIG(status))
+    } else {
+        format!("exited with code {}", libc::WEXITSTATUS(status))
+    }
+}
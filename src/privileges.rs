@@ -0,0 +1,205 @@
+//! Switch to an unprivileged user once listeners are bound
+use std::ffi::{CStr, CString};
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::ptr;
+
+use libc;
+
+/// Target credentials, names or numeric ids
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Privileges
+{
+    pub user: Option<String>,
+    pub group: Option<String>,
+    /// Supplementary groups, those of `user` by default
+    pub groups: Option<Vec<String>>,
+    pub chroot: Option<PathBuf>,
+}
+
+/// Resolved user, numeric ids may have no passwd entry
+struct User
+{
+    name: Option<CString>,
+    uid: libc::uid_t,
+    gid: Option<libc::gid_t>,
+}
+
+/// Credentials looked up from `Privileges`
+struct Resolved
+{
+    user: Option<User>,
+    gid: Option<libc::gid_t>,
+    groups: Option<Vec<libc::gid_t>>,
+}
+
+impl Privileges
+{
+    pub(crate) fn is_empty(&self) -> bool
+    {
+        self.user.is_none() && self.group.is_none() && self.groups.is_none() && self.chroot.is_none()
+    }
+
+    /// Look up names ahead, `apply` may run after the terminal is gone
+    pub(crate) fn validate(&self) -> io::Result<()>
+    {
+        self.resolve().map(|_| ())
+    }
+
+    /// Apply every setting or fail, there is no partial success
+    pub(crate) fn apply(&self) -> io::Result<()>
+    {
+        // names are resolved before chroot hides /etc
+        let Resolved { user, gid, groups } = self.resolve()?;
+
+        if unsafe { libc::geteuid() } == 0 {
+            let name = user.as_ref().and_then(|u| u.name.as_ref());
+
+            match (groups, name, gid) {
+                (Some(groups), _, _) => check(unsafe { libc::setgroups(groups.len() as _, groups.as_ptr()) })?,
+                (None, Some(name), Some(gid)) => check(unsafe { libc::initgroups(name.as_ptr(), gid as _) })?,
+                // groups of root must not stay
+                (None, None, Some(gid)) => check(unsafe { libc::setgroups(1, &gid) })?,
+                (None, _, None) => (),
+            }
+        } else if groups.is_some() {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "supplementary groups require root"));
+        }
+
+        if let Some(ref path) = self.chroot {
+            let path = CString::new(path.as_os_str().as_bytes())?;
+            check(unsafe { libc::chroot(path.as_ptr()) })?;
+            check(unsafe { libc::chdir(b"/\0".as_ptr() as *const libc::c_char) })?;
+        }
+
+        if let Some(gid) = gid {
+            check(unsafe { libc::setgid(gid) })?;
+        }
+
+        if let Some(ref user) = user {
+            check(unsafe { libc::setuid(user.uid) })?;
+
+            // root must not be recoverable
+            if user.uid != 0 && unsafe { libc::setuid(0) } == 0 {
+                return Err(io::Error::other("privileges can be regained after setuid"));
+            }
+        }
+
+        let (uid, euid, cur_gid) = unsafe { (libc::getuid(), libc::geteuid(), libc::getgid()) };
+        if user.as_ref().is_some_and(|u| u.uid != uid || u.uid != euid) || gid.is_some_and(|g| g != cur_gid) {
+            return Err(io::Error::other("credentials did not change"));
+        }
+
+        info!("Running as uid {}, gid {}", uid, cur_gid);
+
+        Ok(())
+    }
+
+    /// User, primary and supplementary groups to switch to
+    fn resolve(&self) -> io::Result<Resolved>
+    {
+        let user = match self.user {
+            Some(ref user) => Some(find_user(user)?),
+            None => None,
+        };
+        let gid = match (self.group.as_ref(), user.as_ref()) {
+            (Some(group), _) => Some(find_group(group)?),
+            (None, Some(user)) => match user.gid {
+                Some(gid) => Some(gid),
+                None => return Err(io::Error::new(io::ErrorKind::NotFound, format!("no passwd entry for uid {}, set group", user.uid))),
+            },
+            (None, None) => None,
+        };
+        let groups = match self.groups {
+            Some(ref groups) => Some(groups.iter().map(|g| find_group(g)).collect::<io::Result<Vec<_>>>()?),
+            None => None,
+        };
+
+        Ok(Resolved {
+            user,
+            gid,
+            groups,
+        })
+    }
+}
+
+fn check(result: libc::c_int) -> io::Result<()>
+{
+    match result {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Entry buffer, grown while the system asks for more
+fn lookup<F>(name: &str, mut get: F) -> io::Result<()>
+    where F: FnMut(&mut Vec<libc::c_char>) -> libc::c_int
+{
+    let mut buf: Vec<libc::c_char> = vec![0; 1024];
+
+    loop {
+        match get(&mut buf) {
+            libc::ERANGE if buf.len() < 1 << 20 => {
+                let len = buf.len() * 2;
+                buf.resize(len, 0);
+            }
+            0 => return Ok(()),
+            e => return Err(io::Error::new(io::Error::from_raw_os_error(e).kind(), format!("look up {:?}: {}", name, io::Error::from_raw_os_error(e)))),
+        }
+    }
+}
+
+fn find_user(user: &str) -> io::Result<User>
+{
+    let mut entry: libc::passwd = unsafe { mem::zeroed() };
+    let mut found: *mut libc::passwd = ptr::null_mut();
+    let name = CString::new(user)?;
+
+    let uid = user.parse::<libc::uid_t>().ok();
+
+    lookup(user, |buf| unsafe {
+        match uid {
+            Some(uid) => libc::getpwuid_r(uid, &mut entry, buf.as_mut_ptr(), buf.len(), &mut found),
+            None => libc::getpwnam_r(name.as_ptr(), &mut entry, buf.as_mut_ptr(), buf.len(), &mut found),
+        }
+    })?;
+
+    match (found.is_null(), uid) {
+        (false, _) => Ok(User {
+            name: Some(unsafe { CStr::from_ptr(entry.pw_name) }.to_owned()),
+            uid: entry.pw_uid,
+            gid: Some(entry.pw_gid),
+        }),
+        (true, Some(uid)) => Ok(User {
+            name: None,
+            uid,
+            gid: None,
+        }),
+        (true, None) => Err(io::Error::new(io::ErrorKind::NotFound, format!("no user {:?}", user))),
+    }
+}
+
+fn find_group(group: &str) -> io::Result<libc::gid_t>
+{
+    let mut entry: libc::group = unsafe { mem::zeroed() };
+    let mut found: *mut libc::group = ptr::null_mut();
+    let name = CString::new(group)?;
+
+    let gid = group.parse::<libc::gid_t>().ok();
+
+    lookup(group, |buf| unsafe {
+        match gid {
+            Some(gid) => libc::getgrgid_r(gid, &mut entry, buf.as_mut_ptr(), buf.len(), &mut found),
+            None => libc::getgrnam_r(name.as_ptr(), &mut entry, buf.as_mut_ptr(), buf.len(), &mut found),
+        }
+    })?;
+
+    match (found.is_null(), gid) {
+        (false, _) => Ok(entry.gr_gid),
+        // numeric group without entry is fine
+        (true, Some(gid)) => Ok(gid),
+        (true, None) => Err(io::Error::new(io::ErrorKind::NotFound, format!("no group {:?}", group))),
+    }
+}
@@ -0,0 +1,62 @@
+//! Graceful shutdown
+use std::net::Shutdown;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+
+use Inner;
+
+/// Stop a running `Client`
+///
+/// Listeners stop accepting, idle connections are closed and running
+/// handlers may complete until the deadline, then `run` returns.
+#[derive(Clone)]
+pub struct ShutdownHandle
+{
+    pub(crate) inner: Arc<Inner>,
+}
+
+impl ShutdownHandle
+{
+    /// Start shutdown, connections still served after `timeout` are closed
+    ///
+    /// Does not block, repeated calls are ignored.
+    pub fn shutdown(&self, timeout: Duration)
+    {
+        if self.inner.shutdown.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        info!("Shutdown, {} connections in progress", self.inner.connections.len());
+        *self.inner.drain_timeout.lock().unwrap() = timeout;
+
+        // stop accepting
+        for waker in self.inner.wakers.lock().unwrap().iter() {
+            waker.wake();
+        }
+
+        // unblock connections waiting for the next request
+        for connection in self.inner.connections.list() {
+            if !connection.busy.load(Ordering::SeqCst) {
+                connection.shutdown(Shutdown::Read);
+            }
+        }
+
+        let inner = self.inner.clone();
+        thread::spawn(move || {
+            thread::sleep(timeout);
+
+            for connection in inner.connections.list() {
+                warn!("Shutdown deadline exceeded, close connection");
+                connection.shutdown(Shutdown::Both);
+            }
+        });
+    }
+
+    /// Shutdown has been requested
+    pub fn is_shutdown(&self) -> bool
+    {
+        self.inner.shutdown.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,300 @@
+//! Process signals: shutdown, reload and log reopening
+use std::fmt;
+use std::io;
+use std::io::Read;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+use std::os::unix::net::UnixStream;
+use std::ptr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use libc;
+
+use Inner;
+use shutdown::ShutdownHandle;
+
+/// Write end of the self-pipe, `-1` until installed
+static PIPE: AtomicI32 = AtomicI32::new(-1);
+
+/// Read end of the self-pipe until `run` or the prefork master takes it
+static RECEIVER: AtomicI32 = AtomicI32::new(-1);
+
+/// Reload or reopen callback
+type CallbackFn = dyn Fn() + Send + Sync;
+
+/// Signal actions of a `Client`, see `Client::handle_signals`
+///
+/// * `SIGTERM` drains connections within `drain_timeout` and makes `run` return
+/// * `SIGQUIT` closes connections right away
+/// * `SIGHUP` calls `on_reload`, then `run_prefork` replaces every worker process,
+///   threads of `run` are kept
+/// * `SIGUSR1` calls `on_reopen` and reopens the slowlog, worker processes get it too
+#[derive(Clone)]
+pub struct Signals
+{
+    drain_timeout: Duration,
+    reload: Option<Arc<CallbackFn>>,
+    reopen: Option<Arc<CallbackFn>>,
+}
+
+impl fmt::Debug for Signals
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        f.debug_struct("Signals")
+            .field("drain_timeout", &self.drain_timeout)
+            .field("reload", &self.reload.is_some())
+            .field("reopen", &self.reopen.is_some())
+            .finish()
+    }
+}
+
+impl Default for Signals
+{
+    fn default() -> Signals
+    {
+        Signals {
+            drain_timeout: Duration::from_secs(30),
+            reload: None,
+            reopen: None,
+        }
+    }
+}
+
+impl Signals
+{
+    /// Constructor
+    pub fn new() -> Signals
+    {
+        Signals::default()
+    }
+
+    /// Time given to connections in progress on `SIGTERM`
+    pub fn drain_timeout(&mut self, timeout: Duration) -> &mut Signals
+    {
+        self.drain_timeout = timeout;
+
+        self
+    }
+
+    /// Called on `SIGHUP`, e.g. to read the configuration again
+    ///
+    /// Threads of `run` keep running, swap shared state here or call `Client::upgrade`.
+    pub fn on_reload<F: Fn() + Send + Sync + 'static>(&mut self, callback: F) -> &mut Signals
+    {
+        self.reload = Some(Arc::new(callback));
+
+        self
+    }
+
+    /// Called on `SIGUSR1`, e.g. `Daemon::reopen` after logrotate
+    pub fn on_reopen<F: Fn() + Send + Sync + 'static>(&mut self, callback: F) -> &mut Signals
+    {
+        self.reopen = Some(Arc::new(callback));
+
+        self
+    }
+}
+
+/// Route signals to the self-pipe, once per process
+///
+/// No thread is started here, `run_prefork` forks after it.
+pub(crate) fn install() -> io::Result<()>
+{
+    listen(&[libc::SIGTERM, libc::SIGQUIT, libc::SIGHUP, libc::SIGUSR1])
+}
+
+/// Run the actions on a thread, unless none are installed or it runs already
+pub(crate) fn spawn(inner: Arc<Inner>) -> io::Result<()>
+{
+    let mut rx = match take() {
+        Some(rx) => rx,
+        None => return Ok(()),
+    };
+
+    thread::Builder::new()
+        .name("gfcgi-signals".to_owned())
+        .spawn(move || {
+            let mut buf = [0; 16];
+            loop {
+                match rx.read(&mut buf) {
+                    Ok(0) => return,
+                    Ok(n) => {
+                        for &signal in &buf[..n] {
+                            dispatch(&inner, signal as libc::c_int);
+                        }
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                    Err(e) => {
+                        error!("Read signals: {}", e);
+                        return;
+                    }
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+/// Read end of the self-pipe, for the prefork master to run the actions in its own loop
+pub(crate) fn take() -> Option<UnixStream>
+{
+    match RECEIVER.swap(-1, Ordering::SeqCst) {
+        -1 => None,
+        fd => Some(unsafe { UnixStream::from_raw_fd(fd) }),
+    }
+}
+
+/// Run the actions of signals received so far, `rx` is nonblocking
+pub(crate) fn dispatch_pending(inner: &Arc<Inner>, rx: &mut UnixStream)
+{
+    let mut buf = [0; 16];
+    loop {
+        match rx.read(&mut buf) {
+            Ok(0) => return,
+            Ok(n) => {
+                for &signal in &buf[..n] {
+                    dispatch(inner, signal as libc::c_int);
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+            Err(e) => {
+                error!("Read signals: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// In a forked worker the master decides on shutdown and reload,
+/// the worker only reopens logs
+pub(crate) fn after_fork(inner: Arc<Inner>) -> io::Result<()>
+{
+    if inner.signals.lock().unwrap().is_none() {
+        return Ok(());
+    }
+
+    for &signal in &[libc::SIGTERM, libc::SIGQUIT, libc::SIGHUP] {
+        action(signal, libc::SIG_IGN)?;
+    }
+
+    // the inherited pipe leads to the master, which reads it in its own loop
+    for pipe in &[&PIPE, &RECEIVER] {
+        let fd = pipe.swap(-1, Ordering::SeqCst);
+        if fd >= 0 {
+            unsafe { libc::close(fd) };
+        }
+    }
+
+    listen(&[libc::SIGUSR1])?;
+    spawn(inner)
+}
+
+fn listen(signals: &[libc::c_int]) -> io::Result<()>
+{
+    let (rx, tx) = UnixStream::pair()?;
+    // a full pipe must not block the handler, the signal is dropped then
+    tx.set_nonblocking(true)?;
+
+    if PIPE.compare_exchange(-1, tx.as_raw_fd(), Ordering::SeqCst, Ordering::SeqCst).is_err() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, "signal handlers are installed already"));
+    }
+    // the handler writes here for the process lifetime
+    let _ = tx.into_raw_fd();
+    RECEIVER.store(rx.into_raw_fd(), Ordering::SeqCst);
+
+    for &signal in signals {
+        action(signal, on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t)?;
+    }
+
+    Ok(())
+}
+
+fn action(signal: libc::c_int, handler: libc::sighandler_t) -> io::Result<()>
+{
+    unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = handler;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+
+        match libc::sigaction(signal, &action, ptr::null_mut()) {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Async-signal-safe part, only writes the signal number
+extern "C" fn on_signal(signal: libc::c_int)
+{
+    let fd = PIPE.load(Ordering::SeqCst);
+    if fd < 0 {
+        return;
+    }
+
+    unsafe {
+        // the interrupted code may be about to read errno
+        let errno = *errno_location();
+        let byte = signal as u8;
+        libc::write(fd, &byte as *const u8 as *const libc::c_void, 1);
+        *errno_location() = errno;
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+unsafe fn errno_location() -> *mut libc::c_int
+{
+    libc::__errno_location()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+unsafe fn errno_location() -> *mut libc::c_int
+{
+    libc::__error()
+}
+
+fn dispatch(inner: &Arc<Inner>, signal: libc::c_int)
+{
+    let signals = match *inner.signals.lock().unwrap() {
+        Some(ref signals) => signals.clone(),
+        None => return,
+    };
+    let shutdown = ShutdownHandle {
+        inner: inner.clone(),
+    };
+
+    match signal {
+        libc::SIGTERM => {
+            info!("SIGTERM, drain connections");
+            shutdown.shutdown(signals.drain_timeout);
+        }
+        libc::SIGQUIT => {
+            info!("SIGQUIT, close connections");
+            shutdown.shutdown(Duration::from_secs(0));
+        }
+        libc::SIGHUP => {
+            info!("SIGHUP, reload");
+            if let Some(ref reload) = signals.reload {
+                reload();
+            }
+            // threads of `run` share the state `on_reload` swapped, only processes are replaced
+            if inner.scoreboard.lock().unwrap().is_some() {
+                inner.reload.store(true, Ordering::SeqCst);
+            }
+        }
+        libc::SIGUSR1 => {
+            info!("SIGUSR1, reopen logs");
+            if let Some(ref reopen) = signals.reopen {
+                reopen();
+            }
+            inner.log_generation.fetch_add(1, Ordering::SeqCst);
+            inner.reopen.store(true, Ordering::SeqCst);
+        }
+        _ => (),
+    }
+}
@@ -0,0 +1,273 @@
+//! Pool status like php-fpm `pm.status_path`
+use std::fmt::Write as FmtWrite;
+use std::io::Write;
+use std::time::Duration;
+
+use http::{Request, Response, Role};
+use {Client, Handler};
+
+/// Snapshot of a `Client` activity, see `Client::status`
+#[derive(Debug, Clone)]
+pub struct Status
+{
+    /// Worker threads of `run` or processes of `run_prefork`
+    pub workers: usize,
+    /// Workers running the handler
+    pub active: usize,
+    pub idle: usize,
+    /// Connections accepted since start
+    pub accepted: u64,
+    /// Requests handled since start
+    pub requests: u64,
+    /// Connections waiting to be accepted on all listeners, `None` where the system does not tell
+    pub listen_queue: Option<usize>,
+    pub running: Vec<RunningRequest>,
+}
+
+/// Request in the handler
+#[derive(Debug, Clone)]
+pub struct RunningRequest
+{
+    /// Thread name or process id
+    pub worker: String,
+    pub request_uri: String,
+    pub elapsed: Duration,
+}
+
+/// Output of `Status::render`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusFormat
+{
+    Plain,
+    Json,
+    /// Prometheus text exposition
+    Prometheus,
+}
+
+impl StatusFormat
+{
+    /// Pick format by query string as php-fpm does: `?json`, `?prometheus` or `?openmetrics`
+    pub fn from_query(query: &str) -> StatusFormat
+    {
+        let has = |name: &str| query.split('&').any(|p| p.split('=').next() == Some(name));
+
+        if has("json") {
+            StatusFormat::Json
+        } else if has("prometheus") || has("openmetrics") {
+            StatusFormat::Prometheus
+        } else {
+            StatusFormat::Plain
+        }
+    }
+
+    pub fn content_type(self) -> &'static str
+    {
+        match self {
+            StatusFormat::Plain => "text/plain",
+            StatusFormat::Json => "application/json",
+            StatusFormat::Prometheus => "text/plain; version=0.0.4",
+        }
+    }
+}
+
+impl Status
+{
+    pub fn render(&self, format: StatusFormat) -> String
+    {
+        match format {
+            StatusFormat::Plain => self.plain(),
+            StatusFormat::Json => self.json(),
+            StatusFormat::Prometheus => self.prometheus(),
+        }
+    }
+
+    fn plain(&self) -> String
+    {
+        let mut out = String::new();
+        let _ = writeln!(out, "accepted conn:    {}", self.accepted);
+        let _ = writeln!(out, "requests:         {}", self.requests);
+        let _ = writeln!(out, "listen queue:     {}", self.listen_queue.map_or("-".to_owned(), |q| q.to_string()));
+        let _ = writeln!(out, "idle workers:     {}", self.idle);
+        let _ = writeln!(out, "active workers:   {}", self.active);
+        let _ = writeln!(out, "total workers:    {}", self.workers);
+
+        for running in &self.running {
+            let _ = writeln!(out, "\nworker:           {}", running.worker);
+            let _ = writeln!(out, "request uri:      {}", running.request_uri);
+            let _ = writeln!(out, "request duration: {}", millis(running.elapsed));
+        }
+
+        out
+    }
+
+    fn json(&self) -> String
+    {
+        let running: Vec<String> = self.running.iter()
+            .map(|r| format!(
+                "{{\"worker\":{},\"request uri\":{},\"request duration\":{}}}",
+                json_str(&r.worker), json_str(&r.request_uri), millis(r.elapsed),
+            ))
+            .collect();
+
+        format!(
+            "{{\"accepted conn\":{},\"requests\":{},\"listen queue\":{},\"idle workers\":{},\"active workers\":{},\"total workers\":{},\"running\":[{}]}}",
+            self.accepted, self.requests,
+            self.listen_queue.map_or("null".to_owned(), |q| q.to_string()),
+            self.idle, self.active, self.workers,
+            running.join(","),
+        )
+    }
+
+    fn prometheus(&self) -> String
+    {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(&str, u64)]| {
+            let _ = writeln!(out, "# HELP gfcgi_{} {}", name, help);
+            let _ = writeln!(out, "# TYPE gfcgi_{} {}", name, kind);
+            for &(labels, value) in samples {
+                let _ = writeln!(out, "gfcgi_{}{} {}", name, labels, value);
+            }
+        };
+
+        metric("accepted_connections_total", "counter", "Connections accepted since start.", &[("", self.accepted)]);
+        metric("requests_total", "counter", "Requests handled since start.", &[("", self.requests)]);
+        metric("workers", "gauge", "Workers by state.", &[
+            ("{state=\"active\"}", self.active as u64),
+            ("{state=\"idle\"}", self.idle as u64),
+        ]);
+        if let Some(queue) = self.listen_queue {
+            metric("listen_queue", "gauge", "Connections waiting to be accepted.", &[("", queue as u64)]);
+        }
+
+        out
+    }
+}
+
+fn millis(duration: Duration) -> u64
+{
+    duration.as_secs() * 1000 + u64::from(duration.subsec_millis())
+}
+
+fn json_str(value: &str) -> String
+{
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+
+    out
+}
+
+/// Answers status requests at `path`, passes others to the wrapped handler
+///
+/// The path is matched against `SCRIPT_NAME`, the format is chosen by `QUERY_STRING`.
+pub struct StatusHandler<T>
+{
+    pub(crate) client: Client,
+    pub(crate) path: String,
+    pub(crate) handler: T,
+}
+
+impl<T: Handler> Handler for StatusHandler<T>
+{
+    fn process(&self, request: &mut Request, response: &mut Response)
+    {
+        if request.header(b"SCRIPT_NAME").map(|v| &v[..]) != Some(self.path.as_bytes()) {
+            return self.handler.process(request, response);
+        }
+
+        let query = request.header(b"QUERY_STRING").map(|v| String::from_utf8_lossy(v).into_owned());
+        let format = StatusFormat::from_query(query.as_ref().map_or("", |q| &q[..]));
+
+        response.status(200)
+            .header_utf8("Content-type", format.content_type())
+            .header_utf8("Cache-Control", "no-cache");
+
+        if let Err(e) = response.write_all(self.client.status().render(format).as_bytes()) {
+            warn!("Send status: {}", e);
+        }
+    }
+
+    fn supports(&self, role: Role) -> bool
+    {
+        self.handler.supports(role)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::time::Duration;
+
+    use super::{json_str, RunningRequest, Status, StatusFormat};
+
+    fn status() -> Status
+    {
+        Status {
+            workers: 3,
+            active: 1,
+            idle: 2,
+            accepted: 10,
+            requests: 12,
+            listen_queue: None,
+            running: vec![RunningRequest {
+                worker: "worker-1".to_owned(),
+                request_uri: "/a\"b\n".to_owned(),
+                elapsed: Duration::from_millis(1500),
+            }],
+        }
+    }
+
+    #[test]
+    fn json()
+    {
+        assert_eq!(json_str("a\"b\\c\n\u{1}é"), "\"a\\\"b\\\\c\\u000a\\u0001é\"");
+
+        assert_eq!(
+            status().render(StatusFormat::Json),
+            "{\"accepted conn\":10,\"requests\":12,\"listen queue\":null,\"idle workers\":2,\"active workers\":1,\"total workers\":3,\
+             \"running\":[{\"worker\":\"worker-1\",\"request uri\":\"/a\\\"b\\u000a\",\"request duration\":1500}]}"
+        );
+    }
+
+    #[test]
+    fn plain()
+    {
+        let mut status = status();
+        status.listen_queue = Some(4);
+        let out = status.render(StatusFormat::Plain);
+
+        assert!(out.starts_with("accepted conn:    10\nrequests:         12\nlisten queue:     4\n"));
+        assert!(out.contains("\nworker:           worker-1\n"));
+        assert!(out.ends_with("request duration: 1500\n"));
+    }
+
+    #[test]
+    fn prometheus()
+    {
+        let out = status().render(StatusFormat::Prometheus);
+
+        assert!(out.contains("# TYPE gfcgi_requests_total counter\ngfcgi_requests_total 12\n"));
+        assert!(out.contains("gfcgi_workers{state=\"active\"} 1\ngfcgi_workers{state=\"idle\"} 2\n"));
+        assert!(!out.contains("listen_queue"));
+    }
+
+    #[test]
+    fn from_query()
+    {
+        assert_eq!(StatusFormat::from_query("a=1&json"), StatusFormat::Json);
+        assert_eq!(StatusFormat::from_query("prometheus"), StatusFormat::Prometheus);
+        assert_eq!(StatusFormat::from_query("openmetrics=1"), StatusFormat::Prometheus);
+        assert_eq!(StatusFormat::from_query("jsonp=1"), StatusFormat::Plain);
+        assert_eq!(StatusFormat::from_query(""), StatusFormat::Plain);
+    }
+}
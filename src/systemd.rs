@@ -0,0 +1,99 @@
+//! systemd socket activation, see `sd_listen_fds(3)`
+use std::env;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::process;
+
+use libc;
+
+/// First passed file descriptor
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Name of a socket without `FileDescriptorName=`
+const DEFAULT_NAME: &str = "unknown";
+
+/// Collect sockets passed by the service manager
+///
+/// Returns nothing when the variables are absent or meant for another process.
+/// Variables are removed so spawned children do not take the sockets.
+pub(crate) fn listen_fds() -> io::Result<Vec<(String, RawFd)>>
+{
+    let pid = env::var("LISTEN_PID").ok();
+    let fds = env::var("LISTEN_FDS").ok();
+    let names = env::var("LISTEN_FDNAMES").ok();
+
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    let result = sockets(pid.as_deref(), fds.as_deref(), names.as_deref(), process::id())?;
+    for &(_, fd) in &result {
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(result)
+}
+
+/// Names and descriptors the variables tell `own_pid`
+fn sockets(pid: Option<&str>, fds: Option<&str>, names: Option<&str>, own_pid: u32) -> io::Result<Vec<(String, RawFd)>>
+{
+    let (pid, fds) = match (pid, fds) {
+        (Some(pid), Some(fds)) => (pid, fds),
+        _ => return Ok(Vec::new()),
+    };
+
+    if parse(pid, "LISTEN_PID")? != own_pid {
+        return Ok(Vec::new());
+    }
+
+    let count = parse(fds, "LISTEN_FDS")? as RawFd;
+
+    let names: Vec<String> = match names {
+        Some(names) => names.split(':').map(String::from).collect(),
+        None => vec![DEFAULT_NAME.to_owned(); count as usize],
+    };
+
+    if names.len() != count as usize {
+        return Err(invalid(format!("LISTEN_FDNAMES has {} names for {} sockets", names.len(), count)));
+    }
+
+    Ok(names.into_iter()
+        .zip(LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .collect())
+}
+
+fn parse(value: &str, name: &str) -> io::Result<u32>
+{
+    value.parse().map_err(|_| invalid(format!("{} is not a number: {:?}", name, value)))
+}
+
+fn invalid(message: String) -> io::Error
+{
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::io;
+
+    use super::sockets;
+
+    #[test]
+    fn listen_fds()
+    {
+        assert_eq!(sockets(Some("42"), Some("2"), None, 42).unwrap(), vec![("unknown".to_owned(), 3), ("unknown".to_owned(), 4)]);
+        assert_eq!(sockets(Some("42"), Some("2"), Some("web:admin"), 42).unwrap(), vec![("web".to_owned(), 3), ("admin".to_owned(), 4)]);
+
+        // meant for another process or not activated
+        assert!(sockets(Some("41"), Some("2"), None, 42).unwrap().is_empty());
+        assert!(sockets(None, Some("2"), None, 42).unwrap().is_empty());
+        assert!(sockets(Some("42"), None, None, 42).unwrap().is_empty());
+
+        for &(pid, fds, names) in &[("x", "2", None), ("42", "two", None), ("42", "2", Some("web"))] {
+            assert_eq!(sockets(Some(pid), Some(fds), names, 42).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
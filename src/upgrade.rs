@@ -0,0 +1,201 @@
+//! Zero-downtime restart, listening sockets are passed to a new copy of the process
+use std::env;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time::Duration;
+
+use libc;
+
+/// Comma-separated descriptors of passed listening sockets,
+/// `SO_REUSEPORT` sockets of one listener are joined by `+`
+const FDS_VAR: &str = "GFCGI_UPGRADE_FDS";
+
+/// Descriptor the new process writes to when it is ready
+const READY_VAR: &str = "GFCGI_UPGRADE_READY";
+
+/// Descriptor of the locked pidfile, both processes share the lock
+const PIDFILE_VAR: &str = "GFCGI_UPGRADE_PIDFILE";
+
+/// Locked pidfile of this process passed on by `spawn`, `-1` without one
+static PIDFILE: AtomicI32 = AtomicI32::new(-1);
+
+/// Sockets passed by the previous process
+pub(crate) struct Inherited
+{
+    /// Sockets of each listener, the first one and its `SO_REUSEPORT` siblings
+    pub fds: Vec<Vec<RawFd>>,
+    pub ready: Option<UnixStream>,
+}
+
+/// Take sockets passed by `spawn`, variables are removed for further children
+pub(crate) fn inherited() -> io::Result<Option<Inherited>>
+{
+    let fds = env::var(FDS_VAR).ok();
+    let ready = env::var(READY_VAR).ok();
+
+    env::remove_var(FDS_VAR);
+    env::remove_var(READY_VAR);
+
+    let fds = match fds {
+        Some(fds) => fds,
+        None => return Ok(None),
+    };
+
+    let fds = parse_list(&fds)?;
+
+    let ready = match ready {
+        Some(fd) => Some(take(parse(&fd)?)?),
+        None => None,
+    };
+
+    for &fd in fds.iter().flatten() {
+        set_cloexec(fd, true)?;
+    }
+
+    Ok(Some(Inherited { fds, ready }))
+}
+
+/// Pass the locked pidfile to the process started by `spawn`, `None` once it is closed
+pub(crate) fn pass_pidfile(fd: Option<RawFd>)
+{
+    PIDFILE.store(fd.unwrap_or(-1), Ordering::SeqCst);
+}
+
+/// Take the pidfile passed by `spawn`, the variable is removed for further children
+pub(crate) fn inherited_pidfile() -> io::Result<Option<File>>
+{
+    let fd = env::var(PIDFILE_VAR).ok();
+    env::remove_var(PIDFILE_VAR);
+
+    match fd {
+        Some(fd) => {
+            let fd = parse(&fd)?;
+            set_cloexec(fd, true)?;
+
+            Ok(Some(unsafe { File::from_raw_fd(fd) }))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Tell the previous process it can drain and exit
+pub(crate) fn notify(mut ready: UnixStream)
+{
+    if let Err(e) = ready.write_all(b"1") {
+        warn!("Notify previous process: {}", e);
+    }
+}
+
+/// Start a new copy of the executable with the same arguments and wait until it is ready
+pub(crate) fn spawn(fds: &[Vec<RawFd>], timeout: Duration) -> io::Result<u32>
+{
+    let (mut ready, child_ready) = UnixStream::pair()?;
+    let child_fd = child_ready.as_raw_fd();
+
+    let list = fds.iter()
+        .map(|group| group.iter().map(|fd| fd.to_string()).collect::<Vec<_>>().join("+"))
+        .collect::<Vec<_>>()
+        .join(",");
+
+    let pidfile = match PIDFILE.load(Ordering::SeqCst) {
+        -1 => None,
+        fd => Some(fd),
+    };
+    let inherit: Vec<RawFd> = fds.iter().flatten().cloned().chain(Some(child_fd)).chain(pidfile).collect();
+
+    let mut command = Command::new(env::current_exe()?);
+    command.args(env::args_os().skip(1))
+        .env(FDS_VAR, list)
+        .env(READY_VAR, child_fd.to_string());
+    if let Some(fd) = pidfile {
+        command.env(PIDFILE_VAR, fd.to_string());
+    }
+
+    unsafe {
+        command.pre_exec(move || {
+            for &fd in &inherit {
+                set_cloexec(fd, false)?;
+            }
+
+            Ok(())
+        });
+    }
+
+    let mut child = command.spawn()?;
+    drop(child_ready);
+
+    info!("Started process {}, waiting until it is ready", child.id());
+
+    ready.set_read_timeout(Some(timeout))?;
+    let mut buf = [0; 1];
+    match ready.read(&mut buf) {
+        Ok(1) => Ok(child.id()),
+        result => {
+            // the new process is not usable, keep serving
+            let _ = child.kill();
+            let _ = child.wait();
+
+            Err(match result {
+                Err(e) => e,
+                _ => io::Error::new(io::ErrorKind::UnexpectedEof, "new process exited before it was ready"),
+            })
+        }
+    }
+}
+
+/// Descriptors of `FDS_VAR` grouped by listener
+fn parse_list(list: &str) -> io::Result<Vec<Vec<RawFd>>>
+{
+    list.split(',')
+        .filter(|group| !group.is_empty())
+        .map(|group| group.split('+').map(parse).collect())
+        .collect()
+}
+
+fn parse(fd: &str) -> io::Result<RawFd>
+{
+    fd.trim().parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("invalid descriptor {:?}", fd)))
+}
+
+fn take(fd: RawFd) -> io::Result<UnixStream>
+{
+    set_cloexec(fd, true)?;
+
+    Ok(unsafe { UnixStream::from_raw_fd(fd) })
+}
+
+fn set_cloexec(fd: RawFd, cloexec: bool) -> io::Result<()>
+{
+    let flags = if cloexec { libc::FD_CLOEXEC } else { 0 };
+
+    match unsafe { libc::fcntl(fd, libc::F_SETFD, flags) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::io;
+
+    use super::parse_list;
+
+    #[test]
+    fn fd_list()
+    {
+        assert_eq!(parse_list("3+7+8,4").unwrap(), vec![vec![3, 7, 8], vec![4]]);
+        assert_eq!(parse_list("3,,4").unwrap(), vec![vec![3], vec![4]]);
+        assert!(parse_list("").unwrap().is_empty());
+
+        for list in &["3,x", "3+", "-"] {
+            assert_eq!(parse_list(list).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
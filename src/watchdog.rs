@@ -0,0 +1,118 @@
+//! Request timeout and slow request log
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use builder::Config;
+use connection::Current;
+use Inner;
+
+/// Interval of checking running requests
+const TICK: Duration = Duration::from_millis(100);
+
+/// Slow request log settings
+#[derive(Debug, Clone)]
+pub(crate) struct Slowlog
+{
+    /// Log file, `log` crate is used without it
+    pub path: Option<PathBuf>,
+    pub timeout: Duration,
+}
+
+/// Check running requests until shutdown and every connection is closed
+pub(crate) fn run(inner: &Inner)
+{
+    let config = &inner.config;
+
+    let mut generation = inner.log_generation.load(Ordering::SeqCst);
+    let mut file = open(config);
+
+    while !inner.shutdown.load(Ordering::SeqCst) || inner.connections.len() > 0 {
+        thread::sleep(TICK);
+
+        // rotated away
+        let current = inner.log_generation.load(Ordering::SeqCst);
+        if current != generation {
+            generation = current;
+            file = open(config);
+        }
+
+        for connection in inner.connections.list() {
+            let timed_out = match *connection.current.lock().unwrap() {
+                Some(ref mut current) => check(config, file.as_mut(), current),
+                None => continue,
+            };
+
+            // not under the lock, the handler takes it to end the request
+            if let Some(id) = timed_out {
+                if let Err(e) = connection.cut(id, 504, b"Request timeout") {
+                    warn!("Send timeout response: {}", e);
+                }
+            }
+        }
+    }
+}
+
+/// Log the request when slow, the id is returned once it has to be cut off
+fn check(config: &Config, file: Option<&mut File>, current: &mut Current) -> Option<u16>
+{
+    let elapsed = current.started.elapsed();
+
+    if let Some(ref slowlog) = config.slowlog {
+        if !current.slow_logged && elapsed >= slowlog.timeout {
+            current.slow_logged = true;
+            log_slow(file, current);
+        }
+    }
+
+    match config.request_timeout {
+        Some(timeout) if !current.timed_out && elapsed >= timeout => {
+            current.timed_out = true;
+            warn!("Request {} exceeded {:?}, answer 504", current.request_uri, timeout);
+            Some(current.id)
+        }
+        _ => None,
+    }
+}
+
+fn open(config: &Config) -> Option<File>
+{
+    config.slowlog.as_ref()
+        .and_then(|slowlog| slowlog.path.as_ref())
+        .and_then(|path| match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => Some(file),
+            Err(e) => {
+                error!("Open slowlog {}: {}", path.display(), e);
+                None
+            }
+        })
+}
+
+/// Backtraces of other threads can not be captured, the thread running the handler is named instead
+fn log_slow(file: Option<&mut File>, current: &Current)
+{
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let elapsed = current.started.elapsed();
+
+    let entry = format!(
+        "[{}.{:03}] pid {}, thread {}\nscript_name: {}\nrequest_uri: {}\nduration: {}.{:03}s\n\n",
+        now.as_secs(), now.subsec_millis(),
+        process::id(), current.thread,
+        current.script_name,
+        current.request_uri,
+        elapsed.as_secs(), elapsed.subsec_millis(),
+    );
+
+    match file {
+        Some(file) => {
+            if let Err(e) = file.write_all(entry.as_bytes()) {
+                warn!("Write slowlog: {}", e);
+            }
+        }
+        None => warn!("Slow request: {}", entry.trim_end().replace('\n', ", ")),
+    }
+}